@@ -3,12 +3,13 @@ use std::path::{Path, PathBuf};
 use std::process::{Command, ExitStatus};
 use std::{env, fmt};
 
-use toml::{Value, map::Map};
+use toml::Value;
 
 use cli::Args;
+use config::Profile;
 use errors::*;
 use extensions::CommandExt;
-use util;
+use {util, xargo};
 use sysroot::XargoMode;
 use xargo::Home;
 
@@ -18,10 +19,22 @@ pub struct Rustflags {
 }
 
 impl Rustflags {
+    pub fn new(flags: Vec<String>) -> Rustflags {
+        Rustflags { flags }
+    }
+
     pub fn hash<H>(&self, hasher: &mut H)
     where
         H: Hasher,
     {
+        for flag in self.hashed() {
+            flag.hash(hasher);
+        }
+    }
+
+    /// Returns the flags that are part of the hash
+    pub fn hashed(&self) -> Vec<&str> {
+        let mut hashed = vec![];
         let mut flags = self.flags.iter();
 
         while let Some(flag) = flags.next() {
@@ -30,22 +43,83 @@ impl Rustflags {
                     if next.starts_with("link-arg=") || next.starts_with("link-args=") {
                         // don't hash linker arguments
                     } else {
-                        flag.hash(hasher);
-                        next.hash(hasher);
+                        hashed.push(&**flag);
+                        hashed.push(&**next);
                     }
                 } else {
-                    flag.hash(hasher);
+                    hashed.push(&**flag);
                 }
             } else {
-                flag.hash(hasher);
+                hashed.push(&**flag);
             }
         }
+
+        hashed
     }
 
     pub fn push(&mut self, flag: impl Into<String>) {
         self.flags.push(flag.into())
     }
 
+    /// Whether these flags instrument the code, which requires the
+    /// `profiler_builtins` runtime to be part of the sysroot
+    pub fn needs_profiler_runtime(&self) -> bool {
+        self.flags.iter().enumerate().any(|(i, flag)| {
+            if flag == "-C" {
+                self.flags.get(i + 1).map(|next| is_instrumentation(next)).unwrap_or(false)
+            } else {
+                flag.starts_with("-C") && is_instrumentation(&flag[2..])
+            }
+        })
+    }
+
+    /// Returns the flags the sysroot is built with
+    ///
+    /// rustc makes every instrumented crate depend on `profiler_builtins`,
+    /// which is itself a sysroot crate built on top of `core`, so the sysroot
+    /// can't be instrumented. The sysroot gets the runtime the instrumented
+    /// project links against instead, and the coverage is the project's.
+    pub fn for_sysroot(&self) -> Rustflags {
+        let mut flags = vec![];
+        let mut iter = self.flags.iter();
+
+        while let Some(flag) = iter.next() {
+            if flag == "-C" {
+                match iter.next() {
+                    Some(next) if is_instrumentation(next) => {}
+                    Some(next) => {
+                        flags.push(flag.clone());
+                        flags.push(next.clone());
+                    }
+                    None => flags.push(flag.clone()),
+                }
+            } else if !(flag.starts_with("-C") && is_instrumentation(&flag[2..])) {
+                flags.push(flag.clone());
+            }
+        }
+
+        Rustflags { flags }
+    }
+
+    /// Returns these flags plus those of `rustc_flags` (`cargo rustc -- ..`)
+    /// that match one of `propagate`
+    ///
+    /// Cargo only passes `rustc_flags` to the final `rustc` invocation, but
+    /// some of them, like `-C target-cpu`, change the ABI so the sysroot must
+    /// be built with them too.
+    pub fn with_rustc_flags(&self, rustc_flags: &[String], propagate: &[String]) -> Rustflags {
+        let mut flags = self.flags.clone();
+
+        for (key, words) in split_rustc_flags(rustc_flags) {
+            let flag = key.split(' ').next().unwrap_or("");
+            if propagate.iter().any(|p| *p == key || p == flag) {
+                flags.extend(words);
+            }
+        }
+
+        Rustflags { flags }
+    }
+
     /// Stringifies these flags for Xargo consumption
     pub fn build_for_xargo(&self, home: &Home) -> String {
         let mut flags = self.flags.clone();
@@ -55,6 +129,89 @@ impl Rustflags {
     }
 }
 
+/// Whether the codegen option `opt` (`-C $opt`) enables instrumentation
+fn is_instrumentation(opt: &str) -> bool {
+    let mut parts = opt.splitn(2, '=');
+    let name = parts.next().unwrap_or("");
+    let enabled = !matches!(parts.next(), Some("n") | Some("no") | Some("off") | Some("false"));
+
+    (name == "instrument-coverage" || name == "profile-generate") && enabled
+}
+
+/// The flags of `cargo rustc -- ..` that reach the sysroot unless
+/// `rustc.propagate` in `Xargo.toml` says otherwise
+const PROPAGATE: &[&str] = &[
+    "-C code-model",
+    "-C relocation-model",
+    "-C soft-float",
+    "-C target-cpu",
+    "-C target-feature",
+];
+
+/// Returns the flags of `xargo rustc -- ..` the sysroot is built with
+///
+/// `xtoml`'s `rustc.propagate` overrides the default list, `PROPAGATE`.
+pub fn propagated_rustc_flags(xtoml: Option<&xargo::Toml>) -> Result<Vec<String>> {
+    match xtoml.and_then(|t| t.propagate()) {
+        Some(value) => value
+            .as_array()
+            .and_then(|array| {
+                array
+                    .iter()
+                    .map(|v| v.as_str().map(|s| s.to_owned()))
+                    .collect::<Option<Vec<_>>>()
+            })
+            .ok_or_else(|| "Xargo.toml: `rustc.propagate` must be an array of strings".into()),
+        None => Ok(PROPAGATE.iter().map(|s| (*s).to_owned()).collect()),
+    }
+}
+
+/// Splits `rustc` flags into `(key, words)` pairs, where `key` names the flag
+/// (e.g. `-C target-cpu` for `-Ctarget-cpu=x`) and `words` are the arguments
+/// that make it up, in the `-C target-cpu=x` form
+fn split_rustc_flags(flags: &[String]) -> Vec<(String, Vec<String>)> {
+    // Flags whose value is either the next argument or glued to the flag
+    const WITH_VALUE: &[&str] = &[
+        "-A", "-C", "-D", "-F", "-L", "-W", "-Z", "-l", "-o", "--cap-lints", "--cfg",
+        "--codegen", "--crate-name", "--crate-type", "--edition", "--emit", "--extern",
+        "--out-dir", "--print",
+    ];
+
+    let mut units = vec![];
+    let mut iter = flags.iter();
+    while let Some(arg) = iter.next() {
+        let prefix = WITH_VALUE.iter().find(|&&f| {
+            arg == f || (arg.starts_with(f) && (f.len() == 2 || arg[f.len()..].starts_with('=')))
+        });
+
+        let (flag, value) = match prefix {
+            Some(&f) if arg == f => match iter.next() {
+                Some(value) => (f, value.clone()),
+                None => {
+                    units.push((arg.clone(), vec![arg.clone()]));
+                    continue;
+                }
+            },
+            Some(&f) => (f, arg[f.len()..].trim_start_matches('=').to_owned()),
+            None => {
+                units.push((arg.clone(), vec![arg.clone()]));
+                continue;
+            }
+        };
+
+        let flag = if flag == "--codegen" { "-C" } else { flag };
+        let key = if flag == "-C" || flag == "-Z" {
+            format!("{} {}", flag, value.split('=').next().unwrap_or(""))
+        } else {
+            flag.to_owned()
+        };
+
+        units.push((key, vec![flag.to_owned(), value]));
+    }
+
+    units
+}
+
 impl fmt::Display for Rustflags {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         let len = self.flags.len();
@@ -68,15 +225,15 @@ impl fmt::Display for Rustflags {
     }
 }
 
-pub fn rustflags(config: Option<&Config>, target: &str) -> Result<Rustflags> {
-    flags(config, target, "rustflags").map(|fs| Rustflags { flags: fs })
-}
-
 pub struct Rustdocflags {
     flags: Vec<String>,
 }
 
 impl Rustdocflags {
+    pub fn new(flags: Vec<String>) -> Rustdocflags {
+        Rustdocflags { flags }
+    }
+
     /// Stringifies these flags for Xargo consumption
     pub fn for_xargo(mut self, home: &Home) -> String {
         self.flags.push("--sysroot".to_owned());
@@ -85,153 +242,101 @@ impl Rustdocflags {
     }
 }
 
-pub fn rustdocflags(config: Option<&Config>, target: &str) -> Result<Rustdocflags> {
-    flags(config, target, "rustdocflags").map(|fs| Rustdocflags { flags: fs })
-}
-
+/// How many nested Xargo invocations to allow before assuming that Xargo is
+/// calling itself in a loop
+const MAX_RECURSION: u32 = 5;
 
-/// Returns the flags for `tool` (e.g. rustflags)
-///
-/// This looks into the environment and into `.cargo/config`
-fn flags(config: Option<&Config>, target: &str, tool: &str) -> Result<Vec<String>> {
-    if let Some(t) = env::var_os(tool.to_uppercase()) {
-        return Ok(
-            t.to_string_lossy()
-                .split_whitespace()
-                .map(|w| w.to_owned())
-                .collect(),
-        );
-    }
+/// Counts the nested Xargo invocations
+const RECURSION_VAR: &str = "XARGO_RECURSION_COUNT";
 
-    if let Some(config) = config.as_ref() {
-        let mut build = false;
-        if let Some(array) = config
-            .table
-            .get("target")
-            .and_then(|t| t.get(target))
-            .and_then(|t| t.get(tool))
-            .or_else(|| {
-                build = true;
-                config.table.get("build").and_then(|t| t.get(tool))
-            }) {
-            let mut flags = vec![];
-
-            let mut error = false;
-            if let Some(array) = array.as_array() {
-                for value in array {
-                    if let Some(flag) = value.as_str() {
-                        flags.push(flag.to_owned());
-                    } else {
-                        error = true;
-                        break;
-                    }
-                }
-            } else {
-                error = true;
-            }
+fn recursion_count() -> u32 {
+    env::var(RECURSION_VAR)
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(0)
+}
 
-            if error {
-                if build {
-                    Err(format!(
-                        ".cargo/config: build.{} must be an array \
-                         of strings",
-                        tool
-                    ))?
-                } else {
-                    Err(format!(
-                        ".cargo/config: target.{}.{} must be an \
-                         array of strings",
-                        target,
-                        tool
-                    ))?
-                }
-            } else {
-                Ok(flags)
-            }
-        } else {
-            Ok(vec![])
-        }
-    } else {
-        Ok(vec![])
+/// Fails if Xargo has been invoked recursively too many times, e.g. because
+/// some tool pointed `CARGO` at Xargo
+pub fn check_recursion() -> Result<()> {
+    let count = recursion_count();
+    if count >= MAX_RECURSION {
+        bail!(
+            "Xargo invoked itself recursively {} times; `CARGO` may point at Xargo or at a \
+             tool that runs Xargo (it's {})",
+            count,
+            env::var_os("CARGO")
+                .map(|c| format!("`{}`", c.to_string_lossy()))
+                .unwrap_or_else(|| "not set".to_owned())
+        )
     }
-}
 
-pub fn command() -> Command {
-    env::var_os("CARGO")
-        .map(Command::new)
-        .unwrap_or_else(|| Command::new("cargo"))
+    Ok(())
 }
 
-pub fn run(args: &Args, verbose: bool) -> Result<ExitStatus> {
-    command()
-        .args(args.all())
-        .run_and_get_status(verbose)
+lazy_static! {
+    // Resolved once, as every crate of the sysroot runs Cargo
+    static ref PATH: ::std::result::Result<PathBuf, String> = resolve();
 }
 
-pub struct Config {
-    table: Value,
+/// Returns the path to the real Cargo
+///
+/// Cargo sets `CARGO` to itself, but wrappers like cargo-make or cargo-hack
+/// set it to themselves, and running them instead of Cargo would re-enter
+/// the wrapper, or Xargo. If `CARGO` isn't Cargo, ask rustup where Cargo is.
+pub fn path() -> Result<PathBuf> {
+    PATH.clone().map_err(|e| e.into())
 }
 
-impl Config {
-    pub fn target(&self) -> Result<Option<&str>> {
-        if let Some(v) = self.table.get("build").and_then(|t| t.get("target")) {
-            Ok(Some(v.as_str()
-                .ok_or_else(|| format!(".cargo/config: build.target must be a string"))?))
-        } else {
-            Ok(None)
-        }
-    }
-}
+fn resolve() -> ::std::result::Result<PathBuf, String> {
+    let cargo = match env::var_os("CARGO") {
+        Some(cargo) => PathBuf::from(cargo),
+        None => return Ok(PathBuf::from("cargo")),
+    };
 
-pub fn config() -> Result<Option<Config>> {
-    let cd = env::current_dir().chain_err(|| "couldn't get the current directory")?;
+    let is_cargo = cargo
+        .file_stem()
+        .map(|s| s.to_string_lossy().eq_ignore_ascii_case("cargo"))
+        .unwrap_or(false);
+    if is_cargo {
+        return Ok(cargo);
+    }
 
-    if let Some(p) = util::search(&cd, ".cargo/config") {
-        Ok(Some(Config {
-            table: util::parse(&p.join(".cargo/config"))?,
-        }))
-    } else {
-        Ok(None)
+    // Falling back to `CARGO` would only end up running the wrapper again
+    let out = Command::new("rustup")
+        .arg("which")
+        .arg("cargo")
+        .output()
+        .map_err(|e| {
+            format!(
+                "`CARGO` points at `{}`, which isn't Cargo, and rustup couldn't be run to find \
+                 Cargo: {}",
+                cargo.display(),
+                e
+            )
+        })?;
+
+    if !out.status.success() {
+        return Err(format!(
+            "`CARGO` points at `{}`, which isn't Cargo, and `rustup which cargo` failed: {}",
+            cargo.display(),
+            String::from_utf8_lossy(&out.stderr).trim()
+        ));
     }
-}
 
-pub struct Profile<'t> {
-    table: &'t Value,
+    Ok(PathBuf::from(String::from_utf8_lossy(&out.stdout).trim()))
 }
 
-impl<'t> Profile<'t> {
-    pub fn hash<H>(&self, hasher: &mut H)
-    where
-        H: Hasher,
-    {
-        let mut v = self.table.clone();
-
-        // Don't include `lto` in the hash because it doesn't affect compilation
-        // of `.rlib`s
-        if let Value::Table(ref mut table) = v {
-            table.remove("lto");
-
-            // don't hash an empty map
-            if table.is_empty() {
-                return;
-            }
-        }
-
-        v.to_string().hash(hasher);
-    }
+pub fn command() -> Result<Command> {
+    let mut cmd = Command::new(path()?);
+    cmd.env(RECURSION_VAR, (recursion_count() + 1).to_string());
+    Ok(cmd)
 }
 
-impl<'t> fmt::Display for Profile<'t> {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        let mut map = Map::new();
-        map.insert("profile".to_owned(), {
-            let mut map = Map::new();
-            map.insert("release".to_owned(), self.table.clone());
-            Value::Table(map)
-        });
-
-        fmt::Display::fmt(&Value::Table(map), f)
-    }
+pub fn run(args: &Args, verbose: bool) -> Result<ExitStatus> {
+    command()?
+        .args(args.all())
+        .run_and_get_status(verbose)
 }
 
 pub struct Toml {
@@ -244,7 +349,7 @@ impl Toml {
         self.table
             .get("profile")
             .and_then(|t| t.get("release"))
-            .map(|t| Profile { table: t })
+            .map(Profile::new)
     }
 }
 
@@ -282,12 +387,21 @@ pub fn root(mode: XargoMode, manifest_path: Option<&str>) -> Result<Option<Root>
 
 #[derive(Clone, Copy, PartialEq)]
 pub enum Subcommand {
+    Bench,
     Clean,
+    Completions,
     Doc,
+    Doctor,
     Init,
     New,
     Other,
+    Package,
+    Prebuild,
+    Publish,
+    Rustc,
+    Rustdoc,
     Search,
+    Sysroot,
     Update,
 }
 
@@ -296,20 +410,40 @@ impl Subcommand {
         use self::Subcommand::*;
 
         match *self {
-            Clean | Init | New | Search | Update => false,
+            Clean | Completions | Doctor | Init | New | Search | Update => false,
             _ => true,
         }
     }
+
+    /// Whether this subcommand packages the crate, which includes building it
+    /// unless it's told not to verify the package
+    pub fn is_package(&self) -> bool {
+        matches!(*self, Subcommand::Package | Subcommand::Publish)
+    }
+
+    /// Whether this subcommand only produces documentation
+    pub fn is_doc(&self) -> bool {
+        matches!(*self, Subcommand::Doc | Subcommand::Rustdoc)
+    }
 }
 
 impl<'a> From<&'a str> for Subcommand {
     fn from(s: &str) -> Subcommand {
         match s {
+            "bench" => Subcommand::Bench,
             "clean" => Subcommand::Clean,
+            "completions" => Subcommand::Completions,
             "doc" => Subcommand::Doc,
+            "doctor" => Subcommand::Doctor,
             "init" => Subcommand::Init,
             "new" => Subcommand::New,
+            "package" => Subcommand::Package,
+            "prebuild" => Subcommand::Prebuild,
+            "publish" => Subcommand::Publish,
+            "rustc" => Subcommand::Rustc,
+            "rustdoc" => Subcommand::Rustdoc,
             "search" => Subcommand::Search,
+            "sysroot" => Subcommand::Sysroot,
             "update" => Subcommand::Update,
             _ => Subcommand::Other,
         }
@@ -0,0 +1,179 @@
+//! Evaluation of `cfg(..)` expressions as used in `.cargo/config`'s
+//! `[target.'cfg(..)']` tables
+
+use std::iter::Peekable;
+use std::str::CharIndices;
+
+use errors::*;
+
+/// The configuration of a target, as printed by `rustc --print cfg`
+pub struct Cfgs {
+    cfgs: Vec<(String, Option<String>)>,
+}
+
+impl Cfgs {
+    /// Parses the output of `rustc --print cfg`
+    pub fn parse(output: &str) -> Cfgs {
+        let cfgs = output
+            .lines()
+            .map(|line| {
+                let mut parts = line.splitn(2, '=');
+                let name = parts.next().unwrap_or("").trim().to_owned();
+                let value = parts.next().map(|v| v.trim().trim_matches('"').to_owned());
+                (name, value)
+            })
+            .filter(|(name, _)| !name.is_empty())
+            .collect();
+
+        Cfgs { cfgs }
+    }
+
+    /// Whether `spec`, e.g. `cfg(target_os = "none")`, holds for this target
+    pub fn matches(&self, spec: &str) -> Result<bool> {
+        let mut parser = Parser {
+            src: spec,
+            chars: spec.char_indices().peekable(),
+        };
+
+        parser.expect_ident("cfg")?;
+        parser.expect('(')?;
+        let matches = parser.expr(self)?;
+        parser.expect(')')?;
+
+        parser.skip_whitespace();
+        if parser.chars.peek().is_some() {
+            Err(format!("unexpected trailing characters in `{}`", spec))?
+        }
+
+        Ok(matches)
+    }
+
+    fn contains(&self, name: &str, value: Option<&str>) -> bool {
+        self.cfgs
+            .iter()
+            .any(|(n, v)| n == name && v.as_deref() == value)
+    }
+}
+
+struct Parser<'a> {
+    src: &'a str,
+    chars: Peekable<CharIndices<'a>>,
+}
+
+impl<'a> Parser<'a> {
+    /// expr := ('all' | 'any') '(' [expr (',' expr)* [',']] ')'
+    ///       | 'not' '(' expr ')'
+    ///       | ident ['=' string]
+    fn expr(&mut self, cfgs: &Cfgs) -> Result<bool> {
+        let ident = self.ident()?;
+
+        match ident {
+            "all" | "any" => {
+                self.expect('(')?;
+
+                let mut results = vec![];
+                while !self.eat(')') {
+                    results.push(self.expr(cfgs)?);
+
+                    if !self.eat(',') {
+                        self.expect(')')?;
+                        break;
+                    }
+                }
+
+                if ident == "all" {
+                    Ok(results.iter().all(|&r| r))
+                } else {
+                    Ok(results.iter().any(|&r| r))
+                }
+            }
+            "not" => {
+                self.expect('(')?;
+                let result = self.expr(cfgs)?;
+                self.expect(')')?;
+
+                Ok(!result)
+            }
+            name => {
+                if self.eat('=') {
+                    let value = self.string()?;
+
+                    Ok(cfgs.contains(name, Some(value)))
+                } else {
+                    Ok(cfgs.contains(name, None))
+                }
+            }
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        while self.chars.peek().map(|&(_, c)| c.is_whitespace()).unwrap_or(false) {
+            self.chars.next();
+        }
+    }
+
+    fn eat(&mut self, expected: char) -> bool {
+        self.skip_whitespace();
+
+        if self.chars.peek().map(|&(_, c)| c) == Some(expected) {
+            self.chars.next();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<()> {
+        if self.eat(expected) {
+            Ok(())
+        } else {
+            Err(format!("expected `{}` in `{}`", expected, self.src))?
+        }
+    }
+
+    fn expect_ident(&mut self, expected: &str) -> Result<()> {
+        if self.ident()? == expected {
+            Ok(())
+        } else {
+            Err(format!("expected `{}` in `{}`", expected, self.src))?
+        }
+    }
+
+    fn ident(&mut self) -> Result<&'a str> {
+        self.skip_whitespace();
+
+        let start = match self.chars.peek() {
+            Some(&(i, c)) if c.is_alphabetic() || c == '_' => i,
+            _ => Err(format!("expected an identifier in `{}`", self.src))?,
+        };
+
+        let mut end = start;
+        while let Some(&(i, c)) = self.chars.peek() {
+            if c.is_alphanumeric() || c == '_' {
+                end = i + c.len_utf8();
+                self.chars.next();
+            } else {
+                break;
+            }
+        }
+
+        Ok(&self.src[start..end])
+    }
+
+    fn string(&mut self) -> Result<&'a str> {
+        self.expect('"')?;
+
+        let start = match self.chars.peek() {
+            Some(&(i, _)) => i,
+            None => self.src.len(),
+        };
+
+        for (i, c) in &mut self.chars {
+            if c == '"' {
+                return Ok(&self.src[start..i]);
+            }
+        }
+
+        Err(format!("unterminated string in `{}`", self.src))?
+    }
+}
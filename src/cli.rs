@@ -1,13 +1,174 @@
 use std::env;
+use std::io::{self, Write};
 
 use cargo::Subcommand;
 
+/// An option Xargo interprets itself. Like all other arguments, it's still
+/// passed on to Cargo.
+pub struct Opt {
+    pub long: &'static str,
+    pub short: Option<char>,
+    /// Name of the option's value, `None` for flags
+    pub value: Option<&'static str>,
+    pub complete: Complete,
+    pub help: &'static str,
+    /// The subcommand the option belongs to, `None` for global options
+    pub subcommand: Option<&'static str>,
+}
+
+/// What the shell completions offer for the value of an option or the first
+/// argument of a subcommand
+pub enum Complete {
+    Nothing,
+    Files,
+    Targets,
+    Words(&'static [&'static str]),
+}
+
+pub const OPTIONS: &[Opt] = &[
+    Opt {
+        long: "target",
+        short: None,
+        value: Some("TRIPLE"),
+        complete: Complete::Targets,
+        help: "Build for the target triple, or the custom target TRIPLE.json",
+        subcommand: None,
+    },
+    Opt {
+        long: "manifest-path",
+        short: None,
+        value: Some("PATH"),
+        complete: Complete::Files,
+        help: "Path to the Cargo.toml of the project",
+        subcommand: None,
+    },
+    Opt {
+        long: "message-format",
+        short: None,
+        value: Some("FMT"),
+        complete: Complete::Words(&["human", "short", "json"]),
+        help: "Format of the diagnostics, also used for the sysroot build",
+        subcommand: None,
+    },
+    Opt {
+        long: "jobs",
+        short: Some('j'),
+        value: Some("N"),
+        complete: Complete::Nothing,
+        help: "Number of parallel jobs, also used for the sysroot build",
+        subcommand: None,
+    },
+    Opt {
+        long: "targets-file",
+        short: None,
+        value: Some("PATH"),
+        complete: Complete::Files,
+        help: "File listing the targets to prebuild, one per line",
+        subcommand: Some("prebuild"),
+    },
+    Opt {
+        long: "verbose",
+        short: Some('v'),
+        value: None,
+        complete: Complete::Nothing,
+        help: "Print the commands Xargo runs",
+        subcommand: None,
+    },
+    Opt {
+        long: "version",
+        short: Some('V'),
+        value: None,
+        complete: Complete::Nothing,
+        help: "Print the versions of Xargo and Cargo",
+        subcommand: None,
+    },
+    Opt {
+        long: "help",
+        short: Some('h'),
+        value: None,
+        complete: Complete::Nothing,
+        help: "Print this help, followed by the help of Cargo",
+        subcommand: None,
+    },
+];
+
+/// A subcommand Xargo implements itself
+pub struct Command {
+    pub name: &'static str,
+    pub usage: &'static str,
+    pub complete: Complete,
+    pub help: &'static str,
+}
+
+pub const COMMANDS: &[Command] = &[
+    Command {
+        name: "sysroot",
+        usage: "sysroot (link <NAME> | llvm-tools)",
+        complete: Complete::Words(&["link", "llvm-tools"]),
+        help: "Register the sysroot as a toolchain or find its LLVM tools",
+    },
+    Command {
+        name: "prebuild",
+        usage: "prebuild [TRIPLE]...",
+        complete: Complete::Targets,
+        help: "Build the sysroots of several targets",
+    },
+    Command {
+        name: "doctor",
+        usage: "doctor",
+        complete: Complete::Nothing,
+        help: "Check the toolchain, target and environment for common problems",
+    },
+    Command {
+        name: "completions",
+        usage: "completions <SHELL>",
+        complete: Complete::Words(&["bash", "zsh", "fish", "powershell"]),
+        help: "Print the completion script of bash, zsh, fish or powershell",
+    },
+];
+
+/// The Cargo subcommands offered by the shell completions
+pub const CARGO_COMMANDS: &[&str] = &[
+    "bench", "build", "check", "clean", "doc", "init", "new", "package", "publish", "run",
+    "rustc", "rustdoc", "search", "test", "update",
+];
+
+/// Environment variables Xargo reads
+const ENV: &[(&str, &str)] = &[
+    ("XARGO_HOME", "Where the sysroots live, ~/.xargo by default"),
+    ("XARGO_RUST_SRC", "Rust source to build the sysroot from"),
+    ("XARGO_PASS_ENV", "Extra variables the sysroot build gets to see"),
+    ("XARGO_KEEP_TEMP", "Keep the directory the sysroot is staged in"),
+    ("XARGO_SHARED_CACHE", "Share the sysroots of XARGO_HOME between users"),
+    ("XARGO_COVERAGE", "Instrument the project for code coverage"),
+    ("RUST_TARGET_PATH", "Directory of custom target specifications"),
+];
+
+/// Options of Cargo's subcommands that take a value, which mustn't be
+/// mistaken for a positional argument
+///
+/// `cargo install --version` is missing as Xargo has a `--version` flag.
+const CARGO_VALUES: &[&str] = &[
+    "--artifact-dir", "--bench", "--bin", "--branch", "--color", "--config", "--crate-type",
+    "--edition", "--example", "--exclude", "--features", "-F", "--git", "--index", "--limit",
+    "--lockfile-path", "--name", "--out-dir", "--package", "-p", "--path", "--precise",
+    "--print", "--profile", "--registry", "--rev", "--root", "--tag", "--target-dir", "--test",
+    "--token", "--vcs", "--vers", "-Z",
+];
+
 pub struct Args {
     all: Vec<String>,
     subcommand: Option<Subcommand>,
     target: Option<String>,
     message_format: Option<String>,
     manifest_path: Option<String>,  // path to the Cargo toml file given in --manifest-path
+    targets_file: Option<String>,  // file listing the targets for `xargo prebuild`
+    jobs: Option<String>,
+    verbose: bool,
+    version: bool,
+    help: bool,
+    positional: Vec<String>,
+    trailing: Vec<String>,  // arguments after `--`
 }
 
 impl Args {
@@ -28,44 +189,144 @@ impl Args {
     }
 
     pub fn verbose(&self) -> bool {
-        self.all
-            .iter()
-            .any(|a| a == "--verbose" || a == "-v" || a == "-vv")
+        self.verbose
     }
 
     pub fn version(&self) -> bool {
-        self.all.iter().any(|a| a == "--version" || a == "-V")
+        self.version
+    }
+
+    /// `xargo --help`, which prints Xargo's help before Cargo's
+    pub fn help(&self) -> bool {
+        self.help && self.subcommand.is_none()
+    }
+
+    /// Whether `package` or `publish` will build the packaged crate
+    pub fn verify_package(&self) -> bool {
+        !self
+            .all
+            .iter()
+            .any(|a| a == "--no-verify" || a == "--list" || a == "-l")
     }
 
     pub fn manifest_path(&self) -> Option<&str> {
         self.manifest_path.as_ref().map(|s| &**s)
     }
+
+    pub fn jobs(&self) -> Option<&str> {
+        self.jobs.as_ref().map(|s| &**s)
+    }
+
+    pub fn targets_file(&self) -> Option<&str> {
+        self.targets_file.as_ref().map(|s| &**s)
+    }
+
+    /// Arguments that are neither flags nor the values of the flags Xargo
+    /// knows about, starting with the subcommand
+    pub fn positional(&self) -> &[String] {
+        &self.positional
+    }
+
+    /// The flags after `--` in `xargo rustc -- <flags>`, which Cargo passes
+    /// to the final `rustc` invocation
+    pub fn rustc_flags(&self) -> &[String] {
+        if self.subcommand == Some(Subcommand::Rustc) {
+            &self.trailing
+        } else {
+            &[]
+        }
+    }
 }
 
 pub fn args() -> Args {
-    let all = env::args().skip(1).collect::<Vec<_>>();
+    parse(env::args().skip(1).collect())
+}
+
+/// Finds the option `arg` stands for, along with its value if it's part of
+/// `arg`, as in `--jobs=4` or `-j4`
+fn option(arg: &str) -> Option<(&'static Opt, Option<&str>)> {
+    if let Some(long) = arg.strip_prefix("--") {
+        let mut parts = long.splitn(2, '=');
+        let name = parts.next().unwrap_or("");
+        let value = parts.next();
+
+        OPTIONS
+            .iter()
+            .find(|o| o.long == name && (o.value.is_some() || value.is_none()))
+            .map(|o| (o, value))
+    } else {
+        let mut chars = arg.strip_prefix('-')?.chars();
+        let short = chars.next()?;
+        let rest = chars.as_str();
+        let opt = OPTIONS.iter().find(|o| o.short == Some(short))?;
+
+        if opt.value.is_some() {
+            Some((opt, Some(rest).filter(|r| !r.is_empty())))
+        } else if rest.chars().all(|c| c == short) {
+            // `-vv`
+            Some((opt, None))
+        } else {
+            None
+        }
+    }
+}
 
+fn parse(all: Vec<String>) -> Args {
     let mut subcommand = None;
     let mut target = None;
     let mut message_format = None;
     let mut manifest_path = None;
+    let mut targets_file = None;
+    let mut jobs = None;
+    let mut verbose = false;
+    let mut version = false;
+    let mut help = false;
+    let mut positional: Vec<String> = vec![];
+    let mut trailing = vec![];
     {
         let mut args = all.iter();
         while let Some(arg) = args.next() {
-            if !arg.starts_with("-") {
-                subcommand = subcommand.or_else(|| Some(Subcommand::from(&**arg)));
+            // Whatever follows `--` is meant for the subcommand, not for us
+            if arg == "--" {
+                trailing.extend(args.cloned());
+                break;
             }
 
-            if arg == "--target" {
-                target = args.next().map(|s| s.to_owned());
-            } else if arg.starts_with("--target=") {
-                target = arg.splitn(2, '=').nth(1).map(|s| s.to_owned());
-            } else if arg == "--message-format" {
-                message_format = args.next().map(|s| s.to_owned());
-            } else if arg.starts_with("--message-format=") {
-                message_format = arg.splitn(2, '=').nth(1).map(|s| s.to_owned());
-            } else if arg.starts_with("--manifest-path") {
-                manifest_path = args.next().map(|s| s.to_owned());
+            // An option of another subcommand is Cargo's business
+            let known = option(arg).filter(|&(opt, _)| {
+                opt.subcommand.is_none() || opt.subcommand == positional.first().map(|s| &**s)
+            });
+
+            let (opt, value) = match known {
+                Some(option) => option,
+                None => {
+                    if CARGO_VALUES.contains(&&**arg) {
+                        args.next();
+                    } else if !arg.starts_with('-') {
+                        subcommand = subcommand.or_else(|| Some(Subcommand::from(&**arg)));
+                        positional.push(arg.clone());
+                    }
+
+                    continue;
+                }
+            };
+
+            let value = match (opt.value, value) {
+                (Some(_), Some(value)) => Some(value.to_owned()),
+                (Some(_), None) => args.next().cloned(),
+                (None, _) => None,
+            };
+
+            match opt.long {
+                "target" => target = value,
+                "manifest-path" => manifest_path = value,
+                "message-format" => message_format = value,
+                "jobs" => jobs = value,
+                "targets-file" => targets_file = value,
+                "verbose" => verbose = true,
+                "version" => version = true,
+                "help" => help = true,
+                _ => unreachable!(),
             }
         }
     }
@@ -76,5 +337,50 @@ pub fn args() -> Args {
         target,
         message_format,
         manifest_path,
+        targets_file,
+        jobs,
+        verbose,
+        version,
+        help,
+        positional,
+        trailing,
+    }
+}
+
+/// Prints the part of `xargo --help` about Xargo itself
+pub fn help() {
+    let stdout = io::stdout();
+    let mut stdout = stdout.lock();
+
+    writeln!(
+        stdout,
+        "{}\n\n\
+         Usage: xargo [OPTIONS] [COMMAND] [ARGS]...\n\n\
+         Xargo runs Cargo with a sysroot built for the target. All arguments are\n\
+         passed on to Cargo, whose help follows Xargo's.\n\n\
+         Options Xargo understands:",
+        env!("CARGO_PKG_DESCRIPTION")
+    ).ok();
+
+    for opt in OPTIONS {
+        let short = opt.short.map(|s| format!("-{},", s)).unwrap_or_default();
+        let long = match opt.value {
+            Some(value) => format!("--{} <{}>", opt.long, value),
+            None => format!("--{}", opt.long),
+        };
+
+        writeln!(stdout, "  {:<3} {:<32} {}", short, long, opt.help).ok();
+    }
+
+    writeln!(stdout, "\nCommands Xargo adds:").ok();
+    for command in COMMANDS {
+        writeln!(stdout, "  {:<36} {}", command.usage, command.help).ok();
     }
+
+    writeln!(stdout, "\nEnvironment:").ok();
+    for &(var, help) in ENV {
+        writeln!(stdout, "  {:<36} {}", var, help).ok();
+    }
+
+    writeln!(stdout, "\nCargo's help:\n").ok();
 }
@@ -0,0 +1,268 @@
+//! `xargo completions <shell>`
+//!
+//! The scripts are generated from the options and subcommands `cli` knows
+//! about, and complete the binaries of both `xargo` and `xargo-check`.
+
+use std::io::{self, Write};
+
+use cli::{Args, Complete, CARGO_COMMANDS, COMMANDS, OPTIONS};
+use errors::*;
+
+const USAGE: &str = "usage: xargo completions (bash | zsh | fish | powershell)";
+
+/// Lists the built-in targets, for the shells to run
+const TARGETS: &str = "rustc --print target-list 2>/dev/null";
+
+pub fn run(args: &Args) -> Result<()> {
+    let script = match args.positional().get(1).map(|s| &**s) {
+        Some("bash") => bash(),
+        Some("zsh") => zsh(),
+        Some("fish") => fish(),
+        Some("powershell") => powershell(),
+        Some(shell) => bail!("unknown shell `{}`; {}", shell, USAGE),
+        None => bail!(USAGE),
+    };
+
+    io::stdout()
+        .write_all(script.as_bytes())
+        .chain_err(|| "couldn't write the completion script")
+}
+
+/// Names of the subcommands, Xargo's own first
+fn commands() -> Vec<&'static str> {
+    COMMANDS
+        .iter()
+        .map(|c| c.name)
+        .chain(CARGO_COMMANDS.iter().cloned())
+        .collect()
+}
+
+/// Spellings of the options, like `-j` and `--jobs`
+fn options() -> Vec<String> {
+    OPTIONS
+        .iter()
+        .flat_map(|o| {
+            o.short
+                .map(|s| format!("-{}", s))
+                .into_iter()
+                .chain(Some(format!("--{}", o.long)))
+        })
+        .collect()
+}
+
+fn bash() -> String {
+    // Completes the word after `$prev`
+    fn case(pattern: &str, complete: &Complete) -> String {
+        let reply = match *complete {
+            Complete::Nothing => "COMPREPLY=()".to_owned(),
+            Complete::Files => r#"COMPREPLY=($(compgen -f -- "$cur"))"#.to_owned(),
+            Complete::Targets => {
+                format!(r#"COMPREPLY=($(compgen -W "$({})" -- "$cur"))"#, TARGETS)
+            }
+            Complete::Words(words) => {
+                format!(r#"COMPREPLY=($(compgen -W "{}" -- "$cur"))"#, words.join(" "))
+            }
+        };
+
+        format!("        {})\n            {}\n            return\n            ;;\n", pattern, reply)
+    }
+
+    let mut cases = String::new();
+    for opt in OPTIONS.iter().filter(|o| o.value.is_some()) {
+        let pattern = match opt.short {
+            Some(short) => format!("-{}|--{}", short, opt.long),
+            None => format!("--{}", opt.long),
+        };
+
+        cases.push_str(&case(&pattern, &opt.complete));
+    }
+    for command in COMMANDS {
+        cases.push_str(&case(command.name, &command.complete));
+    }
+
+    format!(
+        r#"_xargo() {{
+    local cur="${{COMP_WORDS[COMP_CWORD]}}"
+    local prev="${{COMP_WORDS[COMP_CWORD-1]}}"
+
+    case "$prev" in
+{cases}    esac
+
+    if [ "$COMP_CWORD" -eq 1 ]; then
+        COMPREPLY=($(compgen -W "{commands} {options}" -- "$cur"))
+    else
+        COMPREPLY=($(compgen -W "{options}" -- "$cur"))
+    fi
+}}
+
+complete -o default -F _xargo xargo xargo-check
+"#,
+        cases = cases,
+        commands = commands().join(" "),
+        options = options().join(" "),
+    )
+}
+
+fn zsh() -> String {
+    fn action(complete: &Complete) -> String {
+        match *complete {
+            Complete::Nothing => " ".to_owned(),
+            Complete::Files => "_files".to_owned(),
+            Complete::Targets => format!("{{compadd -- $({})}}", TARGETS),
+            Complete::Words(words) => format!("({})", words.join(" ")),
+        }
+    }
+
+    let mut specs = String::new();
+    for opt in OPTIONS {
+        let names = match (opt.short, opt.value) {
+            (Some(short), Some(_)) => format!("'(-{0} --{1})'{{-{0}+,--{1}=}}'", short, opt.long),
+            (Some(short), None) => format!("'*'{{-{},--{}}}'", short, opt.long),
+            (None, Some(_)) => format!("'--{}=", opt.long),
+            (None, None) => format!("'--{}", opt.long),
+        };
+        let value = match opt.value {
+            Some(value) => format!(":{}:{}", value, action(&opt.complete)),
+            None => String::new(),
+        };
+
+        specs.push_str(&format!("        {}[{}]{}' \\\n", names, opt.help, value));
+    }
+
+    let mut cases = String::new();
+    for command in COMMANDS {
+        cases.push_str(&format!(
+            "                {})\n                    _arguments '1:arg:{}'\n                    ;;\n",
+            command.name,
+            action(&command.complete)
+        ));
+    }
+
+    format!(
+        r#"#compdef xargo xargo-check
+
+_xargo() {{
+    local state
+
+    _arguments -s \
+{specs}        '1:command:({commands})' \
+        '*::arg:->args'
+
+    case $state in
+        args)
+            case $words[1] in
+{cases}                *)
+                    _files
+                    ;;
+            esac
+            ;;
+    esac
+}}
+
+_xargo "$@"
+"#,
+        specs = specs,
+        commands = commands().join(" "),
+        cases = cases,
+    )
+}
+
+fn fish() -> String {
+    fn values(complete: &Complete) -> String {
+        match *complete {
+            Complete::Nothing => " -x".to_owned(),
+            Complete::Files => " -r -F".to_owned(),
+            Complete::Targets => format!(" -x -a '({})'", TARGETS),
+            Complete::Words(words) => format!(" -x -a '{}'", words.join(" ")),
+        }
+    }
+
+    let mut script = String::new();
+    for opt in OPTIONS {
+        let short = opt.short.map(|s| format!(" -s {}", s)).unwrap_or_default();
+        let value = opt.value.map(|_| values(&opt.complete)).unwrap_or_default();
+
+        script.push_str(&format!(
+            "complete -c xargo{} -l {}{} -d '{}'\n",
+            short, opt.long, value, opt.help
+        ));
+    }
+
+    for command in COMMANDS {
+        script.push_str(&format!(
+            "complete -c xargo -n __fish_use_subcommand -f -a {} -d '{}'\n",
+            command.name, command.help
+        ));
+        script.push_str(&format!(
+            "complete -c xargo -n '__fish_seen_subcommand_from {}'{}\n",
+            command.name,
+            values(&command.complete)
+        ));
+    }
+
+    // Cargo's completions cover its subcommands and the rest of its options
+    script.push_str("complete -c xargo -w cargo\n");
+    script.push_str("complete -c xargo-check -w xargo\n");
+    script
+}
+
+fn powershell() -> String {
+    fn words(complete: &Complete) -> String {
+        match *complete {
+            Complete::Nothing => "@()".to_owned(),
+            Complete::Files => "Get-ChildItem -Name".to_owned(),
+            Complete::Targets => "rustc --print target-list".to_owned(),
+            Complete::Words(words) => format!("@('{}')", words.join("', '")),
+        }
+    }
+
+    let mut cases = String::new();
+    for opt in OPTIONS.iter().filter(|o| o.value.is_some()) {
+        let pattern = match opt.short {
+            Some(short) => format!("{{ $_ -in '-{}', '--{}' }}", short, opt.long),
+            None => format!("{{ $_ -eq '--{}' }}", opt.long),
+        };
+
+        cases.push_str(&format!("        {} {{ {} }}\n", pattern, words(&opt.complete)));
+    }
+    for command in COMMANDS {
+        cases.push_str(&format!(
+            "        {{ $_ -eq '{}' }} {{ {} }}\n",
+            command.name,
+            words(&command.complete)
+        ));
+    }
+
+    let quote = |words: Vec<String>| format!("'{}'", words.join("', '"));
+
+    format!(
+        r#"Register-ArgumentCompleter -Native -CommandName 'xargo', 'xargo-check' -ScriptBlock {{
+    param($wordToComplete, $commandAst, $cursorPosition)
+
+    $words = @($commandAst.CommandElements | ForEach-Object {{ $_.ToString() }})
+    $position = $words.Count
+    if ($wordToComplete) {{
+        $position -= 1
+    }}
+    $prev = $words[$position - 1]
+
+    $candidates = switch ($prev) {{
+{cases}        default {{
+            if ($position -eq 1 -and -not $wordToComplete.StartsWith('-')) {{
+                @({commands})
+            }} else {{
+                @({options})
+            }}
+        }}
+    }}
+
+    $candidates | Where-Object {{ $_ -like "$wordToComplete*" }} | ForEach-Object {{
+        [System.Management.Automation.CompletionResult]::new($_, $_, 'ParameterValue', $_)
+    }}
+}}
+"#,
+        cases = cases,
+        commands = quote(commands().into_iter().map(|c| c.to_owned()).collect()),
+        options = quote(options()),
+    )
+}
@@ -0,0 +1,426 @@
+//! Cargo's configuration, as far as Xargo is concerned
+//!
+//! Settings come from three layers which, like in Cargo, take precedence in
+//! this order:
+//!
+//! - the command line, e.g. `--target` or `--jobs`
+//! - the environment, e.g. `RUSTFLAGS` or `CARGO_TARGET_$TRIPLE_RUNNER`
+//! - `.cargo/config`, e.g. `build.rustflags` or `target.$triple.runner`
+//!
+//! The exception are the flags: like in Cargo, `CARGO_BUILD_RUSTFLAGS` and
+//! `CARGO_TARGET_$TRIPLE_RUSTFLAGS` extend `.cargo/config` rather than
+//! replace it.
+//!
+//! Every setting is resolved here, in one place, so that the sysroot and the
+//! project never disagree about it.
+
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::{env, fmt};
+
+use rustc_version::VersionMeta;
+use toml::{Value, map::Map};
+
+use CompilationMode;
+use cargo::{Root, Rustdocflags, Rustflags};
+use cli::Args;
+use errors::*;
+use rustc::Target;
+use {rustc, util};
+
+/// Cargo's configuration as seen from the current directory
+pub struct Config {
+    /// `.cargo/config`, an empty table if there's none
+    table: Value,
+    /// The directory that contains `.cargo/config`
+    root: Option<PathBuf>,
+    /// `--target`
+    target: Option<String>,
+    /// `--jobs`
+    jobs: Option<String>,
+}
+
+impl Config {
+    /// Loads the `.cargo/config` closest to the current directory and the
+    /// settings given on the command line
+    pub fn new(args: &Args) -> Result<Config> {
+        let cd = env::current_dir().chain_err(|| "couldn't get the current directory")?;
+
+        let (table, root) = if let Some(p) = util::search(&cd, ".cargo/config") {
+            (util::parse(&p.join(".cargo/config"))?, Some(p.to_owned()))
+        } else {
+            (Value::Table(Default::default()), None)
+        };
+
+        Ok(Config {
+            table,
+            root,
+            target: args.target().map(|s| s.to_owned()),
+            jobs: args.jobs().map(|s| s.to_owned()),
+        })
+    }
+
+    /// The target to build for: `--target`, `CARGO_BUILD_TARGET` or
+    /// `build.target`
+    ///
+    /// A target specification from the environment or `.cargo/config` is
+    /// returned as an absolute path.
+    pub fn target(&self) -> Result<Option<String>> {
+        if let Some(ref target) = self.target {
+            return Ok(Some(target.clone()));
+        }
+
+        // Like Cargo, resolve a relative path to a target specification from
+        // the current directory, or from the directory that holds `.cargo`
+        if let Ok(target) = env::var("CARGO_BUILD_TARGET") {
+            if target.ends_with(".json") {
+                let cd = env::current_dir().chain_err(|| "couldn't get the current directory")?;
+                return Ok(Some(cd.join(target).display().to_string()));
+            } else {
+                return Ok(Some(target));
+            }
+        }
+
+        match self.table.get("build").and_then(|t| t.get("target")) {
+            Some(v) => {
+                let target = v
+                    .as_str()
+                    .ok_or(".cargo/config: build.target must be a string")?;
+
+                if target.ends_with(".json") {
+                    Ok(Some(self.path(target).display().to_string()))
+                } else {
+                    Ok(Some(target.to_owned()))
+                }
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// The compilation mode for `target()`, native compilation if there's no
+    /// target. `None` if the target is unknown.
+    ///
+    /// The flags, the sysroot and its hash must all be derived from this.
+    pub fn cmode(
+        &self,
+        meta: &VersionMeta,
+        root: &Root,
+        verbose: bool,
+    ) -> Result<Option<CompilationMode>> {
+        match self.target()? {
+            Some(ref triple) if self.target.is_some() => ::cmode(triple, meta, root, verbose),
+            // `--target` doesn't take specification files
+            Some(ref json) if json.ends_with(".json") => {
+                Ok(Some(CompilationMode::Cross(Target::from_json(Path::new(json))?)))
+            }
+            // Unlike `--target`, a target from the environment or
+            // `.cargo/config` is cross compiled even if it's the host
+            Some(ref triple) => Ok(Target::new(triple, root, verbose)?.map(CompilationMode::Cross)),
+            None => Ok(Some(CompilationMode::Native(meta.host.clone()))),
+        }
+    }
+
+    /// The flags to compile the project, and the sysroot, with
+    pub fn rustflags(&self, cmode: &CompilationMode, verbose: bool) -> Result<Rustflags> {
+        let mut rustflags = Rustflags::new(self.flags(cmode, "rustflags", verbose)?);
+
+        // `XARGO_COVERAGE` instruments the project without having to spell out
+        // the flags in every place they are configured
+        if coverage() && !rustflags.needs_profiler_runtime() {
+            rustflags.push("-C");
+            rustflags.push("instrument-coverage");
+        }
+
+        Ok(rustflags)
+    }
+
+    /// The flags to document the project with
+    pub fn rustdocflags(&self, cmode: &CompilationMode, verbose: bool) -> Result<Rustdocflags> {
+        self.flags(cmode, "rustdocflags", verbose).map(Rustdocflags::new)
+    }
+
+    /// The linker of the target
+    ///
+    /// Like Cargo, this uses the first of these sources that is set:
+    ///
+    /// - `CARGO_TARGET_$TRIPLE_LINKER`
+    /// - `target.$triple.linker` in `.cargo/config`
+    /// - the first `target.'cfg(..)'.linker` that matches the target in
+    ///   `.cargo/config`
+    pub fn linker(&self, cmode: &CompilationMode, verbose: bool) -> Result<Option<PathBuf>> {
+        if let Some(linker) = env::var_os(target_var(cmode.triple(), "LINKER")) {
+            return Ok(Some(PathBuf::from(linker)));
+        }
+
+        match self.target_values(cmode, "linker", verbose)?.into_iter().next() {
+            Some((path, value)) => {
+                let linker = value
+                    .as_str()
+                    .ok_or_else(|| format!(".cargo/config: {} must be a string", path))?;
+
+                // Paths, as opposed to program names, are relative to the
+                // directory that holds `.cargo`
+                if linker.contains('/') || linker.contains('\\') {
+                    Ok(Some(self.path(linker)))
+                } else {
+                    Ok(Some(PathBuf::from(linker)))
+                }
+            }
+            None => Ok(None),
+        }
+    }
+
+    /// The runner of the target, the program and its arguments
+    ///
+    /// Like Cargo, this uses the first of these sources that is set:
+    ///
+    /// - `CARGO_TARGET_$TRIPLE_RUNNER`
+    /// - `target.$triple.runner` in `.cargo/config`
+    /// - the first `target.'cfg(..)'.runner` that matches the target in
+    ///   `.cargo/config`
+    pub fn runner(&self, cmode: &CompilationMode, verbose: bool) -> Result<Option<Vec<String>>> {
+        if let Some(runner) = env_flags(&target_var(cmode.triple(), "RUNNER")) {
+            return Ok(Some(runner));
+        }
+
+        match self.target_values(cmode, "runner", verbose)?.into_iter().next() {
+            Some((path, value)) => parse_flags(value, || path).map(Some),
+            None => Ok(None),
+        }
+    }
+
+    /// The number of parallel jobs: `--jobs`, `CARGO_BUILD_JOBS` or
+    /// `build.jobs`
+    pub fn jobs(&self) -> Result<Option<i64>> {
+        if let Some(ref jobs) = self.jobs {
+            return Ok(Some(
+                jobs.parse()
+                    .map_err(|_| format!("`--jobs` must be an integer, found `{}`", jobs))?,
+            ));
+        }
+
+        if let Ok(jobs) = env::var("CARGO_BUILD_JOBS") {
+            return Ok(Some(jobs.parse().map_err(|_| {
+                format!("CARGO_BUILD_JOBS must be an integer, found `{}`", jobs)
+            })?));
+        }
+
+        match self.table.get("build").and_then(|t| t.get("jobs")) {
+            Some(v) => Ok(Some(
+                v.as_integer()
+                    .ok_or(".cargo/config: build.jobs must be an integer")?,
+            )),
+            None => Ok(None),
+        }
+    }
+
+    /// Returns the flags for `tool` (e.g. rustflags)
+    ///
+    /// Like Cargo, this uses the first of these sources that is set:
+    ///
+    /// - `CARGO_ENCODED_RUSTFLAGS`
+    /// - `RUSTFLAGS`
+    /// - `target.$triple.rustflags` in `.cargo/config`,
+    ///   `CARGO_TARGET_$TRIPLE_RUSTFLAGS` and all the
+    ///   `target.'cfg(..)'.rustflags` that match the target in `.cargo/config`,
+    ///   joined together
+    /// - `build.rustflags` in `.cargo/config` and `CARGO_BUILD_RUSTFLAGS`,
+    ///   joined together
+    fn flags(&self, cmode: &CompilationMode, tool: &str, verbose: bool) -> Result<Vec<String>> {
+        if let Some(t) = env::var_os(format!("CARGO_ENCODED_{}", tool.to_uppercase())) {
+            let t = t.to_string_lossy();
+
+            if t.is_empty() {
+                return Ok(vec![]);
+            } else {
+                return Ok(t.split('\x1f').map(|w| w.to_owned()).collect());
+            }
+        }
+
+        if let Some(flags) = env_flags(&tool.to_uppercase()) {
+            return Ok(flags);
+        }
+
+        // Like the other settings of `.cargo/config`, `target.$triple.$tool`
+        // can be extended from the environment
+        let values = self.target_values(cmode, tool, verbose)?;
+        let triple = format!("target.{}.{}", cmode.triple(), tool);
+        let (own, cfgs) = values.split_at(values.iter().take_while(|v| v.0 == triple).count());
+
+        let mut flags = vec![];
+        for &(ref path, value) in own {
+            flags.extend(parse_flags(value, || path.clone())?);
+        }
+        if let Some(env) = env_flags(&target_var(cmode.triple(), &tool.to_uppercase())) {
+            flags.extend(env);
+        }
+        for &(ref path, value) in cfgs {
+            flags.extend(parse_flags(value, || path.clone())?);
+        }
+
+        if !flags.is_empty() {
+            return Ok(flags);
+        }
+
+        if let Some(value) = self.table.get("build").and_then(|t| t.get(tool)) {
+            flags.extend(parse_flags(value, || format!("build.{}", tool))?);
+        }
+        if let Some(env) = env_flags(&format!("CARGO_BUILD_{}", tool.to_uppercase())) {
+            flags.extend(env);
+        }
+
+        Ok(flags)
+    }
+
+    /// Returns `target.$triple.$key` followed by every `target.'cfg(..)'.$key`
+    /// that matches the target, along with their paths in `.cargo/config`
+    fn target_values(
+        &self,
+        cmode: &CompilationMode,
+        key: &str,
+        verbose: bool,
+    ) -> Result<Vec<(String, &Value)>> {
+        let targets = match self.table.get("target").and_then(|t| t.as_table()) {
+            Some(targets) => targets,
+            None => return Ok(vec![]),
+        };
+
+        let mut values = vec![];
+        let triple = cmode.triple();
+        if let Some(value) = targets.get(triple).and_then(|t| t.get(key)) {
+            values.push((format!("target.{}.{}", triple, key), value));
+        }
+
+        let cfgs = targets
+            .iter()
+            .filter(|&(k, t)| k.starts_with("cfg(") && t.get(key).is_some())
+            .collect::<Vec<_>>();
+
+        // Only ask rustc about the target when there's something to match
+        if !cfgs.is_empty() {
+            let target_cfgs = rustc::cfg(cmode, verbose)?;
+
+            for (k, t) in cfgs {
+                if target_cfgs.matches(k).chain_err(|| format!(".cargo/config: target.{}", k))? {
+                    values.push((format!("target.{}.{}", k, key), &t[key]));
+                }
+            }
+        }
+
+        Ok(values)
+    }
+
+    /// Resolves `path`, from `.cargo/config`, relative to the directory that
+    /// holds `.cargo`
+    fn path(&self, path: &str) -> PathBuf {
+        match self.root {
+            Some(ref root) => root.join(path),
+            None => PathBuf::from(path),
+        }
+    }
+}
+
+/// The `[profile.release]` of `Cargo.toml`, which the sysroot is built with
+pub struct Profile<'t> {
+    table: &'t Value,
+}
+
+impl<'t> Profile<'t> {
+    pub fn new(table: &'t Value) -> Profile<'t> {
+        Profile { table }
+    }
+
+    pub fn hash<H>(&self, hasher: &mut H)
+    where
+        H: Hasher,
+    {
+        if let Some(v) = self.hashed() {
+            v.to_string().hash(hasher);
+        }
+    }
+
+    /// Returns the part of the profile that is hashed, if any
+    pub fn hashed(&self) -> Option<Value> {
+        let mut v = self.table.clone();
+
+        // Don't include `lto` in the hash because it doesn't affect compilation
+        // of `.rlib`s
+        if let Value::Table(ref mut table) = v {
+            table.remove("lto");
+
+            // don't hash an empty map
+            if table.is_empty() {
+                return None;
+            }
+        }
+
+        Some(v)
+    }
+}
+
+impl<'t> fmt::Display for Profile<'t> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut map = Map::new();
+        map.insert("profile".to_owned(), {
+            let mut map = Map::new();
+            map.insert("release".to_owned(), self.table.clone());
+            Value::Table(map)
+        });
+
+        fmt::Display::fmt(&Value::Table(map), f)
+    }
+}
+
+
+/// Whether `XARGO_COVERAGE` asks for coverage
+fn coverage() -> bool {
+    util::env_flag("XARGO_COVERAGE")
+}
+
+/// The whitespace separated flags of the environment variable `name`, if
+/// it's set
+fn env_flags(name: &str) -> Option<Vec<String>> {
+    env::var_os(name).map(|t| {
+        t.to_string_lossy()
+            .split_whitespace()
+            .map(|w| w.to_owned())
+            .collect()
+    })
+}
+
+/// `CARGO_TARGET_$TRIPLE_$KEY`
+fn target_var(triple: &str, key: &str) -> String {
+    let triple = triple
+        .to_uppercase()
+        .chars()
+        .map(|c| if c == '-' || c == '.' { '_' } else { c })
+        .collect::<String>();
+
+    format!("CARGO_TARGET_{}_{}", triple, key)
+}
+
+/// Parses a `.cargo/config` flags value, which is either a space separated
+/// string or an array of strings
+fn parse_flags<F>(value: &Value, path: F) -> Result<Vec<String>>
+where
+    F: FnOnce() -> String,
+{
+    if let Some(s) = value.as_str() {
+        return Ok(s.split_whitespace().map(|w| w.to_owned()).collect());
+    }
+
+    value
+        .as_array()
+        .and_then(|array| {
+            array
+                .iter()
+                .map(|v| v.as_str().map(|s| s.to_owned()))
+                .collect::<Option<Vec<_>>>()
+        })
+        .ok_or_else(|| {
+            format!(
+                ".cargo/config: {} must be a string or an array of strings",
+                path()
+            ).into()
+        })
+}
@@ -0,0 +1,290 @@
+//! `xargo doctor`
+//!
+//! Looks for the usual reasons Xargo doesn't work, all in one pass, and
+//! suggests how to fix each problem it finds.
+
+use std::io::{self, Write};
+use std::path::Path;
+use std::{env, fs, process};
+
+use rustc_version::{Channel, VersionMeta};
+
+use cli::Args;
+use config::Config;
+use errors::*;
+use flock::Filesystem;
+use rustc::{Src, Sysroot, Target};
+use {cargo, rustc, xargo, CompilationMode, XargoMode};
+
+/// Flags that are passed to rustc or rustdoc through the environment
+const FLAGS_ENV: &[&str] = &[
+    "CARGO_ENCODED_RUSTFLAGS",
+    "RUSTFLAGS",
+    "CARGO_ENCODED_RUSTDOCFLAGS",
+    "RUSTDOCFLAGS",
+];
+
+#[derive(Default)]
+struct Doctor {
+    problems: usize,
+}
+
+impl Doctor {
+    fn ok(&self, msg: &str) {
+        writeln!(io::stdout(), "ok: {}", msg).ok();
+    }
+
+    fn note(&self, msg: &str) {
+        writeln!(io::stdout(), "note: {}", msg).ok();
+    }
+
+    fn warning(&self, msg: &str, fix: &str) {
+        writeln!(io::stdout(), "warning: {}\n  fix: {}", msg, fix).ok();
+    }
+
+    fn error(&mut self, msg: &str, fix: &str) {
+        self.problems += 1;
+        writeln!(io::stdout(), "error: {}\n  fix: {}", msg, fix).ok();
+    }
+}
+
+pub fn run(args: &Args, cargo_mode: XargoMode, verbose: bool) -> Result<()> {
+    let mut doctor = Doctor::default();
+
+    if let Some((sysroot, meta)) = toolchain(&mut doctor, verbose) {
+        src(&mut doctor, &sysroot, &meta);
+        target(&mut doctor, args, cargo_mode, &meta, verbose);
+    }
+    flags(&mut doctor);
+    home(&mut doctor);
+
+    // Each problem has already been reported along with its fix, so this
+    // isn't an error of Xargo's to report again
+    match doctor.problems {
+        0 => Ok(()),
+        n => {
+            writeln!(
+                io::stdout(),
+                "found {} problem{}",
+                n,
+                if n == 1 { "" } else { "s" }
+            ).ok();
+
+            process::exit(1)
+        }
+    }
+}
+
+/// The sysroot can only be built by a nightly compiler
+fn toolchain(doctor: &mut Doctor, verbose: bool) -> Option<(Sysroot, VersionMeta)> {
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_owned());
+    let found = rustc::sysroot(verbose).and_then(|sysroot| {
+        rustc::version(verbose).map(|meta| (sysroot, meta))
+    });
+
+    let (sysroot, meta) = match found {
+        Ok(found) => found,
+        Err(e) => {
+            doctor.error(
+                &format!("couldn't run `{}`: {}", rustc, e),
+                "install Rust with rustup, or point RUSTC at a working rustc",
+            );
+            return None;
+        }
+    };
+
+    match meta.channel {
+        Channel::Nightly | Channel::Dev => doctor.ok(&format!(
+            "`{}` is {}, with its sysroot in {}",
+            rustc,
+            meta.short_version_string,
+            sysroot.path().display()
+        )),
+        Channel::Stable | Channel::Beta => doctor.error(
+            &format!(
+                "`{}` is {}, but the sysroot can only be built with a nightly compiler",
+                rustc, meta.short_version_string
+            ),
+            "run `rustup override set nightly` in the project, or `rustup default nightly`",
+        ),
+    }
+
+    Some((sysroot, meta))
+}
+
+/// The sysroot is built from `XARGO_RUST_SRC` or the `rust-src` component
+fn src(doctor: &mut Doctor, sysroot: &Sysroot, meta: &VersionMeta) {
+    fn has_std(src: &Src) -> bool {
+        ["std", "libstd"]
+            .iter()
+            .any(|d| src.path().join(d).join("Cargo.toml").is_file())
+    }
+
+    match Src::from_env() {
+        Some(ref src) if has_std(src) => {
+            doctor.ok(&format!("XARGO_RUST_SRC points at {}", src.path().display()))
+        }
+        Some(ref src) => doctor.error(
+            &format!(
+                "XARGO_RUST_SRC is {}, which has neither std/Cargo.toml nor libstd/Cargo.toml",
+                src.path().display()
+            ),
+            "point XARGO_RUST_SRC at the `library` directory of a Rust checkout",
+        ),
+        None if meta.channel == Channel::Dev => doctor.error(
+            "XARGO_RUST_SRC isn't set, which the dev channel requires",
+            "point XARGO_RUST_SRC at the `library` directory of the compiler's checkout",
+        ),
+        None => match sysroot.src() {
+            Ok(src) => doctor.ok(&format!("rust-src is installed in {}", src.path().display())),
+            Err(_) => doctor.error(
+                "the rust-src component isn't installed",
+                "run `rustup component add rust-src`",
+            ),
+        },
+    }
+}
+
+/// The target, from `--target`, `CARGO_BUILD_TARGET` or `build.target`, has
+/// to be built into rustc or have a specification Xargo can find
+fn target(
+    doctor: &mut Doctor,
+    args: &Args,
+    cargo_mode: XargoMode,
+    meta: &VersionMeta,
+    verbose: bool,
+) {
+    let root = match cargo::root(cargo_mode, args.manifest_path()) {
+        Ok(Some(root)) => root,
+        Ok(None) => {
+            doctor.note("not in a Cargo project, so the target wasn't checked");
+            return;
+        }
+        Err(e) => {
+            doctor.error(&e.to_string(), "run `xargo doctor` from a Cargo project");
+            return;
+        }
+    };
+
+    if let Err(e) = xargo::toml(&root) {
+        doctor.error(&e.to_string(), "fix the syntax of Xargo.toml");
+    }
+
+    let config = Config::new(args);
+    let found = config.and_then(|c| Ok((c.target()?, c.cmode(meta, &root, verbose)?)));
+
+    match found {
+        Ok((None, _)) => {
+            doctor.ok(&format!("no target is configured, so it's the host, {}", meta.host))
+        }
+        Ok((Some(_), Some(CompilationMode::Native(ref host)))) => {
+            doctor.ok(&format!("the target is the host, {}", host))
+        }
+        Ok((Some(_), Some(CompilationMode::Cross(Target::Builtin { ref triple })))) => {
+            doctor.ok(&format!("the target {} is built into rustc", triple))
+        }
+        Ok((Some(_), Some(CompilationMode::Cross(ref target)))) => match target.spec() {
+            Ok(_) => {
+                doctor.ok(&format!("the target {} has a valid specification", target.triple()))
+            }
+            Err(e) => doctor.error(
+                &format!("the specification of {} is broken: {}", target.triple(), e),
+                "fix the JSON of the target specification",
+            ),
+        },
+        Ok((Some(triple), None)) => doctor.error(
+            &format!("couldn't find the target {}", triple),
+            &format!(
+                "put {0}.json in {1} or set RUST_TARGET_PATH to the directory of {0}.json",
+                triple,
+                root.path().display()
+            ),
+        ),
+        Err(e) => doctor.error(&e.to_string(), "check the target in .cargo/config"),
+    }
+}
+
+/// Xargo passes its own `--sysroot`, and Cargo ignores `RUSTFLAGS` when
+/// `CARGO_ENCODED_RUSTFLAGS` is set
+fn flags(doctor: &mut Doctor) {
+    let mut conflicts = false;
+
+    for var in FLAGS_ENV {
+        if let Some(value) = env::var_os(var) {
+            if value.to_string_lossy().contains("--sysroot") {
+                conflicts = true;
+                doctor.error(
+                    &format!("{} passes --sysroot, which overrides the sysroot Xargo builds", var),
+                    &format!("remove --sysroot from {}", var),
+                );
+            }
+        }
+    }
+
+    for tool in &["RUSTFLAGS", "RUSTDOCFLAGS"] {
+        let encoded = format!("CARGO_ENCODED_{}", tool);
+
+        if env::var_os(&encoded).is_some() && env::var_os(tool).is_some() {
+            conflicts = true;
+            doctor.warning(
+                &format!("{} is ignored because {} is set", tool, encoded),
+                &format!("unset one of {} and {}", tool, encoded),
+            );
+        }
+    }
+
+    if !conflicts {
+        doctor.ok("the environment doesn't override Xargo's flags");
+    }
+}
+
+/// Xargo's directory has to be writable, and a sysroot stays locked for as
+/// long as the process building or using it runs
+fn home(doctor: &mut Doctor) {
+    let dir = match xargo::dir() {
+        Ok(dir) => dir,
+        Err(e) => {
+            doctor.error(&e.to_string(), "set XARGO_HOME to a writable directory");
+            return;
+        }
+    };
+
+    let probe = dir.join(format!(".doctor-{}", process::id()));
+    let writable = fs::create_dir_all(&dir).and_then(|_| fs::write(&probe, ""));
+    fs::remove_file(&probe).ok();
+
+    match writable {
+        Ok(()) => doctor.ok(&format!("{} is writable", dir.display())),
+        Err(e) => doctor.error(
+            &format!("{} isn't writable: {}", dir.display(), e),
+            &format!(
+                "fix the permissions of {}, or set XARGO_HOME to a writable directory",
+                dir.display()
+            ),
+        ),
+    }
+
+    for home in &[dir.clone(), dir.join("HOST")] {
+        locks(doctor, &home.join("lib").join("rustlib"));
+    }
+}
+
+fn locks(doctor: &mut Doctor, rustlib: &Path) {
+    let entries = match fs::read_dir(rustlib) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries.filter_map(|e| e.ok()) {
+        let sysroot = Filesystem::new(entry.path());
+
+        // A lock can't go stale: it's released when its holder exits
+        if let Ok(true) = sysroot.is_locked(".sentinel") {
+            doctor.note(&format!(
+                "the sysroot in {} is in use by another Xargo or Cargo, which may make this \
+                 one wait for it",
+                entry.path().display()
+            ));
+        }
+    }
+}
@@ -1,6 +1,8 @@
-use std::io::Write;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
 use std::io;
-use std::process::{Command, ExitStatus};
+use std::process::{Command, ExitStatus, Stdio};
+use std::thread;
 
 use errors::*;
 
@@ -8,6 +10,19 @@ pub trait CommandExt {
     fn run(&mut self, verbose: bool) -> Result<()>;
     fn run_and_get_status(&mut self, verbose: bool) -> Result<ExitStatus>;
     fn run_and_get_stdout(&mut self, verbose: bool) -> Result<String>;
+
+    /// Runs the command to completion, copying all its output to `log`
+    ///
+    /// Lines of output for which `quiet` returns `true` only go to `log`.
+    /// Color codes are left out of `log` and aren't seen by `quiet`. The
+    /// command's stdout goes to Xargo's stderr if `stdout_to_stderr`.
+    fn run_and_log(
+        &mut self,
+        verbose: bool,
+        log: &File,
+        quiet: fn(&[u8]) -> bool,
+        stdout_to_stderr: bool,
+    ) -> Result<()>;
 }
 
 impl CommandExt for Command {
@@ -56,4 +71,89 @@ impl CommandExt for Command {
             ))?
         }
     }
+
+    fn run_and_log(
+        &mut self,
+        verbose: bool,
+        log: &File,
+        quiet: fn(&[u8]) -> bool,
+        stdout_to_stderr: bool,
+    ) -> Result<()> {
+        if verbose {
+            writeln!(io::stderr(), "+ {:?}", self).ok();
+        }
+        writeln!(&*log, "+ {:?}", self).ok();
+
+        let mut child = self.stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .chain_err(|| format!("couldn't execute `{:?}`", self))?;
+
+        let stdout = child.stdout.take().unwrap();
+        let stdout_log = log.try_clone().chain_err(|| "couldn't duplicate the log file handle")?;
+        let thread = thread::spawn(move || if stdout_to_stderr {
+            tee(stdout, io::stderr(), stdout_log, quiet)
+        } else {
+            tee(stdout, io::stdout(), stdout_log, quiet)
+        });
+        tee(child.stderr.take().unwrap(), io::stderr(), log, quiet);
+        thread.join().ok();
+
+        let status = child.wait()
+            .chain_err(|| format!("couldn't execute `{:?}`", self))?;
+
+        if status.success() {
+            Ok(())
+        } else {
+            Err(format!(
+                "`{:?}` failed with exit code: {:?}",
+                self,
+                status.code()
+            ))?
+        }
+    }
+}
+
+/// Copies `src` line by line to `log` and, unless `quiet`, to `dst`
+fn tee<R, W, L>(src: R, mut dst: W, mut log: L, quiet: fn(&[u8]) -> bool)
+where
+    R: Read,
+    W: Write,
+    L: Write,
+{
+    let mut src = BufReader::new(src);
+    let mut line = vec![];
+
+    while src.read_until(b'\n', &mut line).map(|n| n > 0).unwrap_or(false) {
+        let plain = strip_colors(&line);
+        log.write_all(&plain).ok();
+        if !quiet(&plain) {
+            dst.write_all(&line).ok();
+        }
+        line.clear();
+    }
+}
+
+/// Removes the ANSI escape sequences, like `ESC [ 1 ; 32 m`, from `line`
+fn strip_colors(line: &[u8]) -> Vec<u8> {
+    let mut plain = Vec::with_capacity(line.len());
+    let mut bytes = line.iter();
+
+    while let Some(&b) = bytes.next() {
+        if b == 0x1b {
+            // `ESC [`, parameters and a final byte in `@` to `~`
+            if bytes.as_slice().first() == Some(&b'[') {
+                bytes.next();
+                for &b in &mut bytes {
+                    if (0x40..=0x7e).contains(&b) {
+                        break;
+                    }
+                }
+            }
+        } else {
+            plain.push(b);
+        }
+    }
+
+    plain
 }
@@ -1,6 +1,7 @@
 //! Copy paste of Cargo's src/util/flock.rs with modifications to not depend on
 //! other Cargo stuff
 
+use std::ffi::OsStr;
 use std::fs::{File, OpenOptions};
 use std::io::Write;
 use std::path::{Display, Path, PathBuf};
@@ -30,10 +31,18 @@ impl FileLock {
     }
 
     pub fn remove_siblings(&self) -> io::Result<()> {
+        self.remove_siblings_except(|_| false)
+    }
+
+    /// Like `remove_siblings` but keeps the entries for which `keep` is true
+    pub fn remove_siblings_except<F>(&self, keep: F) -> io::Result<()>
+    where
+        F: Fn(&OsStr) -> bool,
+    {
         let path = self.path();
         for entry in path.parent().unwrap().read_dir()? {
             let entry = entry?;
-            if Some(&entry.file_name()[..]) == path.file_name() {
+            if Some(&entry.file_name()[..]) == path.file_name() || keep(&entry.file_name()) {
                 continue;
             }
             let kind = entry.file_type()?;
@@ -125,6 +134,29 @@ impl Filesystem {
         })
     }
 
+    /// Whether another process holds a lock on `path`, without waiting for it
+    pub fn is_locked<P>(&self, path: P) -> io::Result<bool>
+    where
+        P: AsRef<Path>,
+    {
+        let f = File::open(self.path.join(path))?;
+
+        match FileExt::try_lock_exclusive(&f) {
+            Ok(()) => {
+                FileExt::unlock(&f).ok();
+                Ok(false)
+            }
+            Err(ref e) if e.raw_os_error() == fs2::lock_contended_error().raw_os_error() => {
+                Ok(true)
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    pub fn as_path_unlocked(&self) -> &Path {
+        &self.path
+    }
+
     pub fn display(&self) -> Display {
         self.path.display()
     }
@@ -3,9 +3,12 @@
 #[macro_use]
 extern crate error_chain;
 extern crate fs2;
-#[cfg(any(all(target_os = "linux", not(target_env = "musl")), target_os = "macos"))]
+#[macro_use]
+extern crate lazy_static;
+#[cfg(unix)]
 extern crate libc;
 extern crate rustc_version;
+#[macro_use]
 extern crate serde_json;
 extern crate tempdir;
 extern crate toml;
@@ -18,18 +21,26 @@ use std::path::{Path};
 use std::process::ExitStatus;
 use std::{env, io, process};
 
-use rustc_version::Channel;
+use rustc_version::{Channel, VersionMeta};
 
+use cargo::{Root, Subcommand};
+use config::Config;
 use errors::*;
-use rustc::Target;
+use rustc::{Src, Sysroot, Target};
 
 mod cargo;
+mod cfg;
 mod cli;
+mod completions;
+mod config;
+mod doctor;
 mod errors;
 mod extensions;
 mod flock;
+mod prebuild;
 mod rustc;
 mod sysroot;
+mod toolchain;
 mod util;
 mod xargo;
 
@@ -111,10 +122,28 @@ fn run(cargo_mode: XargoMode) -> Result<Option<ExitStatus>> {
     let args = cli::args();
     let verbose = args.verbose();
 
-    let meta = rustc::version().map_err(|_| "could not determine rustc version")?;
+    cargo::check_recursion()?;
+
+    if args.subcommand() == Some(Subcommand::Completions) {
+        return completions::run(&args).map(|_| None);
+    } else if args.subcommand() == Some(Subcommand::Doctor) {
+        return doctor::run(&args, cargo_mode, verbose).map(|_| None);
+    } else if args.help() {
+        cli::help();
+
+        return cargo::run(&args, verbose).map(Some);
+    }
+
+    let meta = rustc::version(verbose)
+        .map_err(|_| "could not determine rustc version")?;
 
     if let Some(sc) = args.subcommand() {
-        if !sc.needs_sysroot() {
+        if sc == Subcommand::Prebuild {
+            let sysroot = rustc::sysroot(verbose)?;
+            return prebuild::run(&args, cargo_mode, &meta, &sysroot, verbose).map(|_| None);
+        }
+
+        if !sc.needs_sysroot() || (sc.is_package() && !args.verify_package()) {
             return cargo::run(&args, verbose).map(Some);
         }
     } else if args.version() {
@@ -127,69 +156,100 @@ fn run(cargo_mode: XargoMode) -> Result<Option<ExitStatus>> {
         return cargo::run(&args, verbose).map(Some);
     }
 
-    let config = cargo::config()?;
-    if let Some(root) = cargo::root(cargo_mode, args.manifest_path())? {
-        // We can't build sysroot with stable or beta due to unstable features
-        let sysroot = rustc::sysroot(verbose)?;
-        let src = match meta.channel {
-            Channel::Dev => rustc::Src::from_env().ok_or(
-                "The XARGO_RUST_SRC env variable must be set and point to the \
-                 Rust source directory when working with the 'dev' channel",
-            )?,
-            Channel::Nightly => if let Some(src) = rustc::Src::from_env() {
-                src
-            } else {
-                sysroot.src()?
-            },
-            Channel::Stable | Channel::Beta => {
-                eprintln!(
-                    "ERROR: the sysroot can't be built for the {:?} channel. \
-                     Switch to nightly.",
-                    meta.channel
-                );
-                process::exit(1);
-            }
-        };
-        let cmode = if let Some(triple) = args.target() {
-            if Path::new(triple).is_file() {
-                bail!(
-                    "Xargo doesn't support files as an argument to --target. \
-                     Use `--target foo` instead of `--target foo.json`."
-                )
-            } else if triple == meta.host {
-                Some(CompilationMode::Native(meta.host.clone()))
-            } else {
-                Target::new(triple, &root, verbose)?.map(CompilationMode::Cross)
-            }
-        } else {
-            if let Some(ref config) = config {
-                if let Some(triple) = config.target()? {
-                    Target::new(triple, &root, verbose)?.map(CompilationMode::Cross)
-                } else {
-                    Some(CompilationMode::Native(meta.host.clone()))
-                }
-            } else {
-                Some(CompilationMode::Native(meta.host.clone()))
+    let sysroot = rustc::sysroot(verbose)?;
+
+    // `xargo sysroot link <name>` / `xargo sysroot llvm-tools`
+    let link = if args.subcommand() == Some(Subcommand::Sysroot) {
+        match args.positional().get(1).map(|s| &**s) {
+            Some("link") => {}
+            Some("llvm-tools") => {
+                return toolchain::llvm_tools(&sysroot, &meta).map(|_| None);
             }
-        };
+            Some(action) => bail!(
+                "unknown `sysroot` action `{}`, expected `link` or `llvm-tools`",
+                action
+            ),
+            None => bail!("usage: xargo sysroot (link <name> | llvm-tools)"),
+        }
+
+        if cargo_mode == XargoMode::Check {
+            bail!("a check-only sysroot can't be linked as a toolchain")
+        }
+
+        Some(args.positional().get(2).ok_or("usage: xargo sysroot link <name>")?)
+    } else {
+        None
+    };
+
+    let config = Config::new(&args)?;
+    if let Some(root) = cargo::root(cargo_mode, args.manifest_path())? {
+        sysroot::sweep(verbose);
+
+        let src = src(&meta, &sysroot)?;
+        let cmode = config.cmode(&meta, &root, verbose)?;
 
         if let Some(cmode) = cmode {
             let home = xargo::home(&cmode)?;
-            let rustflags = cargo::rustflags(config.as_ref(), cmode.triple())?;
+            let rustflags = config.rustflags(&cmode, verbose)?;
+
+            // Documenting a crate for a foreign target only needs the metadata
+            // of the sysroot crates, so there's no need to fully compile them.
+            // In native mode build scripts and proc macros are linked against
+            // the custom sysroot so we still need a complete build.
+            let sysroot_mode = match args.subcommand() {
+                Some(sc) if sc.is_doc() && !cmode.is_native() => XargoMode::Check,
+                _ => cargo_mode,
+            };
+
+            // `xargo rustc -- <flags>` may pass flags that change the ABI
+            let sysroot_flags = if args.rustc_flags().is_empty() {
+                rustflags.clone()
+            } else {
+                let propagate = cargo::propagated_rustc_flags(xargo::toml(&root)?.1.as_ref())?;
+                rustflags.with_rustc_flags(args.rustc_flags(), &propagate)
+            };
 
-            sysroot::update(
+            let home = sysroot::update(
                 &cmode,
-                &home,
+                home,
                 &root,
-                &rustflags,
+                &sysroot_flags,
+                &config,
                 &meta,
                 &src,
                 &sysroot,
                 verbose,
                 args.message_format(),
-                cargo_mode,
+                false,
+                sysroot_mode,
+                args.subcommand() == Some(Subcommand::Bench),
             )?;
 
+            if let Some(name) = link {
+                toolchain::link(name, &cmode, &home, &sysroot, &meta, verbose)?;
+
+                return Ok(None)
+            }
+
+            // Cargo runs the benchmarks through the runner itself, but a
+            // foreign binary without one fails in a confusing way
+            if args.subcommand() == Some(Subcommand::Bench) && !cmode.is_native() {
+                match config.runner(&cmode, verbose)? {
+                    Some(ref runner) if verbose => {
+                        writeln!(io::stderr(), "+ runner: {}", runner.join(" ")).ok();
+                    }
+                    Some(_) => {}
+                    None => {
+                        writeln!(
+                            io::stderr(),
+                            "warning: no runner is configured for {0}; set \
+                             `target.{0}.runner` in .cargo/config to run the benchmarks",
+                            cmode.triple()
+                        ).ok();
+                    }
+                }
+            }
+
             if args.subcommand().is_some() || cargo_mode == XargoMode::Build {
                 return xargo::run(
                     &args,
@@ -197,7 +257,7 @@ fn run(cargo_mode: XargoMode) -> Result<Option<ExitStatus>> {
                     rustflags,
                     &home,
                     &meta,
-                    config.as_ref(),
+                    &config,
                     verbose,
                 ).map(Some);
             } else {
@@ -206,6 +266,53 @@ fn run(cargo_mode: XargoMode) -> Result<Option<ExitStatus>> {
         }
     }
 
+    if link.is_some() {
+        bail!("couldn't find a sysroot to link; run this from a Cargo project")
+    }
+
     cargo::run(&args, verbose).map(Some)
 }
 
+/// Returns the path to the Rust source the sysroot is built from
+fn src(meta: &VersionMeta, sysroot: &Sysroot) -> Result<Src> {
+    // We can't build sysroot with stable or beta due to unstable features
+    Ok(match meta.channel {
+        Channel::Dev => Src::from_env().ok_or(
+            "The XARGO_RUST_SRC env variable must be set and point to the \
+             Rust source directory when working with the 'dev' channel",
+        )?,
+        Channel::Nightly => if let Some(src) = Src::from_env() {
+            src
+        } else {
+            sysroot.src()?
+        },
+        Channel::Stable | Channel::Beta => {
+            eprintln!(
+                "ERROR: the sysroot can't be built for the {:?} channel. \
+                 Switch to nightly.",
+                meta.channel
+            );
+            process::exit(1);
+        }
+    })
+}
+
+/// Returns the compilation mode for `--target triple`, `None` if `triple` is
+/// neither a built-in target nor a custom one Xargo can find
+fn cmode(
+    triple: &str,
+    meta: &VersionMeta,
+    root: &Root,
+    verbose: bool,
+) -> Result<Option<CompilationMode>> {
+    if Path::new(triple).is_file() {
+        bail!(
+            "Xargo doesn't support files as an argument to --target. \
+             Use `--target foo` instead of `--target foo.json`."
+        )
+    } else if triple == meta.host {
+        Ok(Some(CompilationMode::Native(meta.host.clone())))
+    } else {
+        Ok(Target::new(triple, root, verbose)?.map(CompilationMode::Cross))
+    }
+}
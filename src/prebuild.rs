@@ -0,0 +1,113 @@
+use std::path::Path;
+
+use rustc_version::VersionMeta;
+use serde_json::Value;
+
+use cli::Args;
+use config::Config;
+use errors::*;
+use sysroot::XargoMode;
+use rustc::Sysroot;
+use {cargo, sysroot, util, xargo};
+
+/// `xargo prebuild [--targets-file <path>] [<target>...]`
+///
+/// Builds the sysroot of every target without running Cargo on the project
+/// afterwards. Progress is reported as JSON lines on stdout, which is kept for
+/// them alone: all the output of the inner Cargo invocations, including their
+/// JSON messages with `--message-format json`, goes to stderr.
+pub fn run(
+    args: &Args,
+    cargo_mode: XargoMode,
+    meta: &VersionMeta,
+    sysroot: &Sysroot,
+    verbose: bool,
+) -> Result<()> {
+    let mut targets = args.positional()[1..].to_vec();
+    if let Some(file) = args.targets_file() {
+        // One target per line, `#` starts a comment
+        for line in util::read(Path::new(file))?.lines() {
+            let target = line.split('#').next().unwrap_or("").trim();
+            if !target.is_empty() {
+                targets.push(target.to_owned());
+            }
+        }
+    }
+
+    if targets.is_empty() {
+        bail!("usage: xargo prebuild [--targets-file <path>] [<target>...]")
+    }
+
+    // A `Xargo.toml` on its own is enough to prebuild sysroots
+    let root = match cargo::root(XargoMode::Build, args.manifest_path())? {
+        Some(root) => root,
+        None => cargo::root(XargoMode::Check, args.manifest_path())?
+            .ok_or("couldn't find a `Cargo.toml` or `Xargo.toml`")?,
+    };
+    let config = Config::new(args)?;
+
+    sysroot::sweep(verbose);
+
+    let src = ::src(meta, sysroot)?;
+
+    let build = |target: &str| -> Result<()> {
+        let cmode = ::cmode(target, meta, &root, verbose)?
+            .ok_or_else(|| format!("couldn't find the target `{}`", target))?;
+        let home = xargo::home(&cmode)?;
+        let rustflags = config.rustflags(&cmode, verbose)?;
+
+        sysroot::update(
+            &cmode,
+            home,
+            &root,
+            &rustflags,
+            &config,
+            meta,
+            &src,
+            sysroot,
+            verbose,
+            args.message_format(),
+            true,
+            cargo_mode,
+            false,
+        ).map(|_| ())
+    };
+
+    let total = targets.len();
+    let mut failed = 0;
+    for (index, target) in targets.iter().enumerate() {
+        progress(json!({
+            "reason": "sysroot-started",
+            "target": target,
+            "index": index,
+            "total": total,
+        }));
+
+        let result = build(target);
+
+        let mut message = json!({
+            "reason": "sysroot-finished",
+            "target": target,
+            "index": index,
+            "total": total,
+            "success": result.is_ok(),
+        });
+        if let Err(e) = result {
+            failed += 1;
+            message["error"] = Value::String(
+                e.iter().map(|e| e.to_string()).collect::<Vec<_>>().join(": "),
+            );
+        }
+        progress(message);
+    }
+
+    if failed > 0 {
+        bail!("couldn't build the sysroot of {} out of {} targets", failed, total)
+    }
+
+    Ok(())
+}
+
+fn progress(message: Value) {
+    println!("{}", message);
+}
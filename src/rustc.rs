@@ -1,13 +1,17 @@
-use std::env;
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
 use std::hash::{Hash, Hasher};
+use std::io;
 use std::path::{Path, PathBuf};
 use std::process::Command;
+use std::env;
 
-pub use rustc_version::version_meta as version;
-
+use rustc_version::{self, VersionMeta};
 use serde_json::Value;
 use serde_json;
 
+use CompilationMode;
+use cfg::Cfgs;
 use errors::*;
 use extensions::CommandExt;
 use {rustc, util};
@@ -27,17 +31,94 @@ pub fn targets(verbose: bool) -> Result<Vec<String>> {
         .map(|t| t.lines().map(|l| l.to_owned()).collect())
 }
 
+/// `rustc --print cfg --target $target`
+pub fn cfg(cmode: &CompilationMode, verbose: bool) -> Result<Cfgs> {
+    let mut cmd = command();
+    cmd.args(&["--print", "cfg", "--target"]);
+
+    // Custom targets may live in a directory rustc doesn't know about
+    if let CompilationMode::Cross(Target::Custom { ref json, .. }) = *cmode {
+        cmd.arg(json);
+    } else {
+        cmd.arg(cmode.triple());
+    }
+
+    cmd.run_and_get_stdout(verbose).map(|out| Cfgs::parse(&out))
+}
+
+/// Whether `std` can be built for the target
+///
+/// Bare metal targets (`target_os = "none"`) only support `core` and `alloc`
+pub fn supports_std(cmode: &CompilationMode, verbose: bool) -> Result<bool> {
+    cfg(cmode, verbose)?.matches(r#"cfg(not(target_os = "none"))"#)
+}
+
+/// `rustc -vV`
+///
+/// This runs on every invocation, so switching toolchains between invocations
+/// is always noticed.
+pub fn version(verbose: bool) -> Result<VersionMeta> {
+    let out = command().arg("-vV").run_and_get_stdout(verbose)?;
+
+    rustc_version::version_meta_for(&out).chain_err(|| "couldn't parse the output of `rustc -vV`")
+}
+
 /// `rustc --print sysroot`
 pub fn sysroot(verbose: bool) -> Result<Sysroot> {
     command()
         .args(&["--print", "sysroot"])
         .run_and_get_stdout(verbose)
         .map(|l| {
-            Sysroot {
-                path: PathBuf::from(l.trim()),
-            }
+            let path = PathBuf::from(l.trim());
+            let fingerprint = fingerprint(&path).ok();
+
+            Sysroot { path, fingerprint }
         })
 }
+
+/// Cheaply identifies the compiler installed in the sysroot at `path`
+///
+/// This covers the size and modification time of the `rustc` binary and of
+/// `rustc_driver`, which contains the actual compiler. Only their metadata is
+/// read, so this is much cheaper than running `rustc`.
+fn fingerprint(path: &Path) -> io::Result<u64> {
+    fn stamp<H>(path: &Path, hasher: &mut H) -> io::Result<()>
+    where
+        H: Hasher,
+    {
+        let metadata = fs::metadata(path)?;
+        metadata.len().hash(hasher);
+        metadata.modified()?.hash(hasher);
+        Ok(())
+    }
+
+    let mut hasher = DefaultHasher::new();
+    path.hash(&mut hasher);
+
+    stamp(&path.join("bin").join(format!("rustc{}", env::consts::EXE_SUFFIX)), &mut hasher)?;
+
+    // `librustc_driver-*.so` lives in `lib`, `rustc_driver-*.dll` in `bin`
+    for dir in &["lib", "bin"] {
+        let mut drivers = fs::read_dir(path.join(dir))?
+            .filter_map(|e| e.ok())
+            .map(|e| e.path())
+            .filter(|p| {
+                p.file_name()
+                    .map(|n| n.to_string_lossy().contains("rustc_driver"))
+                    .unwrap_or(false)
+            })
+            .collect::<Vec<_>>();
+        drivers.sort();
+
+        for driver in drivers {
+            driver.hash(&mut hasher);
+            stamp(&driver, &mut hasher)?;
+        }
+    }
+
+    Ok(hasher.finish())
+}
+
 /// Path to Rust source
 pub struct Src {
     path: PathBuf,
@@ -62,6 +143,7 @@ impl Src {
 /// Path to `rustc`'s sysroot
 pub struct Sysroot {
     path: PathBuf,
+    fingerprint: Option<u64>,
 }
 
 impl Sysroot {
@@ -69,6 +151,27 @@ impl Sysroot {
         &self.path
     }
 
+    /// Identifies the compiler of this sysroot, if its files could be read
+    pub fn fingerprint(&self) -> Option<u64> {
+        self.fingerprint
+    }
+
+    /// Returns the path to `tool` of the `llvm-tools` component, if installed
+    pub fn llvm_tool(&self, host: &str, tool: &str) -> Option<PathBuf> {
+        let path = self.path()
+            .join("lib")
+            .join("rustlib")
+            .join(host)
+            .join("bin")
+            .join(format!("{}{}", tool, env::consts::EXE_SUFFIX));
+
+        if path.is_file() {
+            Some(path)
+        } else {
+            None
+        }
+    }
+
     /// Returns the path to Rust source, `$SRC`, where `$SRC/libstd/Cargo.toml`
     /// or `$SRC/std/Cargo.toml` exists.
     pub fn src(&self) -> Result<Src> {
@@ -133,6 +236,25 @@ impl Target {
         }
     }
 
+    /// A custom target given by the path to its specification, like
+    /// `build.target = "targets/foo.json"`, whose triple is `foo`
+    pub fn from_json(json: &Path) -> Result<Target> {
+        if !json.is_file() {
+            bail!("couldn't find the target specification {}", json.display())
+        }
+
+        let triple = json
+            .file_stem()
+            .and_then(|s| s.to_str())
+            .ok_or_else(|| format!("{} is not a valid target name", json.display()))?
+            .to_owned();
+
+        Ok(Target::Custom {
+            json: json.to_owned(),
+            triple,
+        })
+    }
+
     pub fn triple(&self) -> &str {
         match *self {
             Target::Builtin { ref triple } => triple,
@@ -144,15 +266,24 @@ impl Target {
     where
         H: Hasher,
     {
-        if let Target::Custom { ref json, .. } = *self {
-            // Here we roundtrip to/from JSON to get the same hash when some
-            // fields of the JSON file has been shuffled around
-            serde_json::from_str::<Value>(&util::read(json)?)
-                .chain_err(|| format!("{} is not valid JSON", json.display()))?
-                .to_string()
-                .hash(hasher);
+        // Here we roundtrip to/from JSON to get the same hash when some
+        // fields of the JSON file has been shuffled around
+        if let Some(spec) = self.spec()? {
+            spec.to_string().hash(hasher);
         }
 
         Ok(())
     }
+
+    /// Returns the specification of a custom target
+    pub fn spec(&self) -> Result<Option<Value>> {
+        if let Target::Custom { ref json, .. } = *self {
+            Ok(Some(
+                serde_json::from_str::<Value>(&util::read(json)?)
+                    .chain_err(|| format!("{} is not valid JSON", json.display()))?,
+            ))
+        } else {
+            Ok(None)
+        }
+    }
 }
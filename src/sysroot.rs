@@ -1,22 +1,29 @@
 use std::collections::BTreeMap;
 use std::collections::hash_map::DefaultHasher;
+use std::ffi::OsString;
 use std::hash::{Hash, Hasher};
+use std::fs::File;
 use std::io::{self, Write};
 use std::path::{Path, PathBuf};
-use std::{env, fs};
+use std::process::Command;
+use std::time::Duration;
+use std::{env, fs, process};
 
 use rustc_version::VersionMeta;
 use tempdir::TempDir;
+use serde_json;
 use toml::{value::Table, Value, map::Map};
+use walkdir::WalkDir;
 
 use CompilationMode;
 use cargo::{Root, Rustflags};
+use config::Config;
 use errors::*;
 use extensions::CommandExt;
 use rustc::{Src, Sysroot, Target};
 use util;
 use xargo::Home;
-use {cargo, xargo};
+use {cargo, rustc, xargo};
 
 fn profile() -> &'static str {
     "release"
@@ -28,11 +35,14 @@ fn build(
     ctoml: &Option<cargo::Toml>,
     home: &Home,
     rustflags: &Rustflags,
+    config: &Config,
     src: &Src,
     sysroot: &Sysroot,
     hash: u64,
+    provenance: &serde_json::Value,
     verbose: bool,
     message_format: Option<&str>,
+    stdout_to_stderr: bool,
     cargo_mode: XargoMode,
 ) -> Result<()> {
     const TOML: &'static str = r#"
@@ -43,12 +53,17 @@ version = "0.0.0"
 "#;
 
     let rustlib = home.lock_rw(cmode.triple())?;
+    rotate_logs(rustlib.parent());
     rustlib
-        .remove_siblings()
+        .remove_siblings_except(|name| name.to_string_lossy().starts_with(LOG))
         .chain_err(|| format!("couldn't clear {}", rustlib.path().display()))?;
     let dst = rustlib.parent().join("lib");
     util::mkdir(&dst)?;
 
+    let log_path = rustlib.parent().join(LOG);
+    let log = File::create(&log_path)
+        .chain_err(|| format!("couldn't create {}", log_path.display()))?;
+
     if cmode.triple().contains("pc-windows-gnu") && cargo_mode == XargoMode::Build {
         let src = &sysroot
             .path()
@@ -88,10 +103,20 @@ version = "0.0.0"
         }
     }
 
+    let env = self::env();
+    let jobs = config.jobs()?;
+    writeln!(
+        &log,
+        "+ environment: {}",
+        env.iter().map(|(name, _, _)| &**name).collect::<Vec<_>>().join(" ")
+    ).ok();
+
     for (_, stage) in blueprint.stages {
-        let td = TempDir::new("xargo").chain_err(|| "couldn't create a temporary directory")?;
+        let keep = env::var_os("XARGO_KEEP_TEMP").is_some();
+        let td = TempDir::new(&staging_prefix(keep))
+            .chain_err(|| "couldn't create a temporary directory")?;
         let tdp;
-        let td = if env::var_os("XARGO_KEEP_TEMP").is_some() {
+        let td = if keep {
             tdp = td.into_path();
             &tdp
         } else {
@@ -131,15 +156,23 @@ version = "0.0.0"
         util::mkdir(&td.join("src"))?;
         util::write(&td.join("src").join("lib.rs"), "")?;
 
-        let cargo = || {
-            let mut cmd = cargo::command();
-            let mut rustflags = rustflags.clone();
+        let cargo = || -> Result<Command> {
+            let mut cmd = cargo::command()?;
+
+            // Only let through the variables that are known to be needed
+            cmd.env_clear();
+            for (name, value, _) in &env {
+                cmd.env(name, value);
+            }
+
+            let mut rustflags = rustflags.for_sysroot();
             rustflags.push("-Z");
             rustflags.push("force-unstable-if-unmarked");
             let flags = rustflags.build_for_xargo(home);
             if verbose {
                 writeln!(io::stderr(), "+ CARGO_ENCODED_RUSTFLAGS={:?}", flags).ok();
             }
+            writeln!(&log, "+ CARGO_ENCODED_RUSTFLAGS={:?}", flags).ok();
             cmd.env("CARGO_ENCODED_RUSTFLAGS", flags);
 
             // Since we currently don't want to respect `.cargo/config` or `CARGO_TARGET_DIR`,
@@ -198,15 +231,29 @@ version = "0.0.0"
                 cmd.args(&["--message-format", format]);
             }
 
-            if verbose {
-                cmd.arg("-v");
+            // `--jobs` and friends apply to the sysroot too
+            if let Some(jobs) = jobs {
+                cmd.arg("-j").arg(jobs.to_string());
             }
 
-            cmd
+            // Always be verbose so the log has the `rustc` invocations
+            cmd.arg("-v");
+
+            // The output goes through a pipe to be logged, so Cargo can't
+            // tell whether it ends up in a terminal
+            if env::var_os("CARGO_TERM_COLOR").is_none() && util::stderr_is_tty() {
+                cmd.arg("--color").arg("always");
+            }
+
+            Ok(cmd)
         };
 
+        let quiet = if verbose { never_quiet } else { is_verbose_output };
         for krate in stage.crates {
-            cargo().arg("-p").arg(krate).run(verbose)?;
+            cargo()?
+                .arg("-p")
+                .arg(krate)
+                .run_and_log(verbose, &log, quiet, stdout_to_stderr)?;
         }
 
         // Copy artifacts to Xargo sysroot
@@ -220,11 +267,325 @@ version = "0.0.0"
     }
 
     // Create hash file
+    if xargo::shared_cache() {
+        write_manifest(rustlib.parent())?;
+    }
+    util::write(&rustlib.parent().join(PROVENANCE), &provenance.to_string())?;
     util::write(&rustlib.parent().join(".hash"), &hash.to_string())?;
+    if xargo::shared_cache() {
+        home.share(cmode.triple())?;
+    }
 
     Ok(())
 }
 
+/// Staging directories older than this were left behind by builds that
+/// crashed or were killed
+const STALE_STAGING: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Returns the prefix of the temporary directories the sysroot is staged in
+///
+/// The directories are named `xargo-$PID.$RANDOM`, or `xargo-keep-$PID.$RANDOM`
+/// if they are kept on purpose (`XARGO_KEEP_TEMP`), which `sweep` leaves alone.
+fn staging_prefix(keep: bool) -> String {
+    if keep {
+        format!("xargo-keep-{}", process::id())
+    } else {
+        format!("xargo-{}", process::id())
+    }
+}
+
+/// Removes the staging directories that crashed or killed builds left behind
+///
+/// These are the `xargo-$PID.$RANDOM` directories whose process is gone,
+/// including the private homes of `xargo::private_home`, and
+/// the `xargo.$RANDOM` ones of older Xargos, which didn't record the PID.
+/// Either kind is only removed once it's older than `STALE_STAGING`.
+pub fn sweep(verbose: bool) {
+    let entries = match fs::read_dir(env::temp_dir()) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries.filter_map(|e| e.ok()) {
+        let orphaned = match entry.file_name().to_str().and_then(staging_pid) {
+            Some(Some(pid)) => !is_running(pid),
+            Some(None) => true,
+            None => false,
+        };
+
+        let stale = orphaned
+            && entry
+                .metadata()
+                .and_then(|m| m.modified())
+                .ok()
+                .and_then(|modified| modified.elapsed().ok())
+                .map(|age| age > STALE_STAGING)
+                .unwrap_or(false);
+
+        if stale {
+            if verbose {
+                writeln!(io::stderr(), "+ rm -r {}", entry.path().display()).ok();
+            }
+
+            // Some other Xargo may be sweeping it too
+            fs::remove_dir_all(entry.path()).ok();
+        }
+    }
+}
+
+/// Whether `name` is a staging directory, along with the PID of the Xargo
+/// that created it if it's known
+fn staging_pid(name: &str) -> Option<Option<u32>> {
+    // `TempDir` appends a dot and 12 random alphanumeric characters
+    fn is_random(s: &str) -> bool {
+        s.len() == 12 && s.bytes().all(|b| b.is_ascii_alphanumeric())
+    }
+
+    if let Some(random) = name.strip_prefix("xargo.") {
+        return if is_random(random) { Some(None) } else { None };
+    }
+
+    let mut parts = name.strip_prefix("xargo-")?.splitn(2, '.');
+    let pid = parts.next()?;
+    if pid.is_empty() || !pid.bytes().all(|b| b.is_ascii_digit()) || !is_random(parts.next()?) {
+        return None;
+    }
+
+    pid.parse().ok().map(Some)
+}
+
+/// Whether the process `pid` is still running
+#[cfg(unix)]
+fn is_running(pid: u32) -> bool {
+    if pid == 0 || pid > ::libc::pid_t::MAX as u32 {
+        return false;
+    }
+
+    // Signal 0 only checks whether the process exists. It may exist but
+    // belong to someone else, in which case `kill` fails with `EPERM`.
+    let exists = unsafe { ::libc::kill(pid as ::libc::pid_t, 0) } == 0;
+
+    exists || io::Error::last_os_error().raw_os_error() != Some(::libc::ESRCH)
+}
+
+/// Whether the process `pid` is still running, which can't be told here, so
+/// the staging directory's age has to do
+#[cfg(not(unix))]
+fn is_running(_: u32) -> bool {
+    false
+}
+
+/// The variables of Xargo's environment that the Cargo building the sysroot
+/// gets to see, on top of those Xargo sets itself. Other variables, like
+/// `RUSTFLAGS` or `CARGO_BUILD_TARGET`, are left out so they can't change the
+/// sysroot behind Xargo's back. A `*` matches any sequence of characters and
+/// names are compared ignoring case, as on Windows.
+///
+/// These only locate tools and files or tune the output, so they are not part
+/// of the sysroot's hash.
+const PASS_ENV: &[&str] = &[
+    // the system
+    "PATH", "HOME", "USER", "USERNAME", "TMPDIR", "TEMP", "TMP", "LANG", "LC_*",
+    "TERM", "COLORTERM", "LD_LIBRARY_PATH", "DYLD_LIBRARY_PATH", "DYLD_FALLBACK_LIBRARY_PATH",
+    "SYSTEMROOT", "SYSTEMDRIVE", "WINDIR", "USERPROFILE", "APPDATA", "LOCALAPPDATA",
+    "PATHEXT", "COMSPEC",
+    // the MSVC developer prompt, where `cc` and the linker look for MSVC
+    "INCLUDE", "LIB", "LIBPATH", "VCINSTALLDIR", "VSINSTALLDIR", "VCToolsInstallDir",
+    "ProgramFiles(x86)", "ProgramData",
+    // the toolchain
+    "CARGO_HOME", "RUSTUP_HOME", "RUSTUP_TOOLCHAIN", "RUST_RECURSION_COUNT",
+    "RUST_TARGET_PATH", "RUSTC_WRAPPER", "SCCACHE_*",
+    // Cargo's output, parallelism and network access
+    "CARGO_TERM_*", "CARGO_BUILD_JOBS", "CARGO_MAKEFLAGS", "MAKEFLAGS", "MFLAGS",
+    "CARGO_HTTP_*", "CARGO_NET_*", "CARGO_REGISTRIES_*", "CARGO_REGISTRY_*",
+    "HTTP_PROXY", "HTTPS_PROXY", "ALL_PROXY", "NO_PROXY", "SSL_CERT_FILE", "SSL_CERT_DIR",
+    // git and ssh, to fetch the `git` dependencies of `Xargo.toml`
+    "GIT_*", "SSH_AUTH_SOCK", "SSH_AGENT_PID", "XDG_*",
+];
+
+/// Like `PASS_ENV` but these can change the sysroot, so they are part of its
+/// hash. So are the variables listed in `XARGO_PASS_ENV`.
+const HASH_ENV: &[&str] = &[
+    "RUSTC", "RUSTC_BOOTSTRAP",
+    // C toolchain, e.g. for `compiler_builtins`' `c` feature
+    "CC", "CXX", "AR", "CFLAGS", "CXXFLAGS", "CC_*", "CXX_*", "AR_*", "CFLAGS_*",
+    "CXXFLAGS_*", "TARGET_CC", "TARGET_CXX", "TARGET_AR", "TARGET_CFLAGS", "TARGET_CXXFLAGS",
+    "HOST_CC", "HOST_CXX", "HOST_AR", "HOST_CFLAGS", "HOST_CXXFLAGS",
+    "CARGO_TARGET_*_LINKER", "CARGO_PROFILE_RELEASE_*",
+    // Apple's SDK and deployment targets, which change what rustc and `cc` emit
+    "MACOSX_DEPLOYMENT_TARGET", "IPHONEOS_DEPLOYMENT_TARGET", "TVOS_DEPLOYMENT_TARGET",
+    "SDKROOT",
+];
+
+/// Returns the variables of Xargo's environment that the Cargo building the
+/// sysroot gets, sorted by name, along with whether they are hashed
+fn env() -> Vec<(String, OsString, bool)> {
+    fn matches(pattern: &str, name: &str) -> bool {
+        let (pattern, name) = (pattern.to_uppercase(), name.to_uppercase());
+        let mut parts = pattern.splitn(2, '*');
+        let prefix = parts.next().unwrap_or("");
+
+        match parts.next() {
+            Some(suffix) => {
+                name.len() >= prefix.len() + suffix.len()
+                    && name.starts_with(prefix)
+                    && name.ends_with(suffix)
+            }
+            None => name == prefix,
+        }
+    }
+
+    let extra = env::var("XARGO_PASS_ENV").unwrap_or_default();
+    let extra = extra.split_whitespace().collect::<Vec<_>>();
+
+    let mut vars = env::vars_os()
+        .filter_map(|(name, value)| name.into_string().ok().map(|name| (name, value)))
+        .filter_map(|(name, value)| {
+            if HASH_ENV.iter().chain(&extra).any(|p| matches(p, &name)) {
+                Some((name, value, true))
+            } else if PASS_ENV.iter().any(|p| matches(p, &name)) {
+                Some((name, value, false))
+            } else {
+                None
+            }
+        })
+        .collect::<Vec<_>>();
+    vars.sort();
+
+    vars
+}
+
+/// Lists the checksum of every file of the sysroot, next to its `lib`
+/// directory
+const MANIFEST: &str = "manifest";
+
+/// Returns the checksums of the files in the `lib` and `bin` directories of
+/// `dir`, sorted by their path relative to `dir`
+fn checksums(dir: &Path) -> Result<BTreeMap<String, u64>> {
+    let mut checksums = BTreeMap::new();
+
+    for sub in &["lib", "bin"] {
+        for e in WalkDir::new(dir.join(sub)) {
+            let e = match e {
+                Ok(e) => e,
+                // no `bin` directory
+                Err(ref e) if e.depth() == 0 => continue,
+                Err(e) => Err(e).chain_err(|| format!("couldn't walk {}", dir.display()))?,
+            };
+
+            if e.file_type().is_file() {
+                let relative = e.path()
+                    .strip_prefix(dir)
+                    .chain_err(|| format!("{} is not in {}", e.path().display(), dir.display()))?
+                    .to_string_lossy()
+                    .replace('\\', "/");
+
+                checksums.insert(relative, util::checksum(e.path())?);
+            }
+        }
+    }
+
+    Ok(checksums)
+}
+
+fn write_manifest(dir: &Path) -> Result<()> {
+    let manifest = checksums(dir)?
+        .iter()
+        .map(|(path, checksum)| format!("{:016x} {}\n", checksum, path))
+        .collect::<String>();
+
+    util::write(&dir.join(MANIFEST), &manifest)
+}
+
+/// Checks `triple`'s sysroot in `home` against its manifest and, if it
+/// doesn't match, clears it so that it's rebuilt in place
+///
+/// Returns whether the sysroot can be used: `false` means it's corrupted but
+/// couldn't be locked or cleared, e.g. because it belongs to someone else.
+fn repair(home: &Home, triple: &str) -> Result<bool> {
+    if !home.rustlib(triple).join(".hash").exists() {
+        return Ok(true);
+    }
+
+    if matches_manifest(home.lock_ro(triple)?.parent())? {
+        return Ok(true);
+    }
+
+    let lock = match home.lock_rw(triple) {
+        Ok(lock) => lock,
+        Err(_) => return Ok(false),
+    };
+
+    // Some other Xargo may have repaired it while we waited for the lock
+    let dir = lock.parent();
+    if !dir.join(".hash").exists() || matches_manifest(dir)? {
+        return Ok(true);
+    }
+
+    writeln!(
+        io::stderr(),
+        "warning: the sysroot of {} in {} doesn't match its manifest; rebuilding it",
+        triple,
+        home.display()
+    ).ok();
+
+    Ok(lock.remove_siblings().is_ok())
+}
+
+/// Checks the sysroot in `dir` against its manifest
+///
+/// A sysroot without a manifest doesn't pass, as it can't be trusted.
+fn matches_manifest(dir: &Path) -> Result<bool> {
+    let manifest = match util::read(&dir.join(MANIFEST)) {
+        Ok(manifest) => manifest,
+        Err(_) => return Ok(false),
+    };
+
+    let mut expected = BTreeMap::new();
+    for line in manifest.lines() {
+        let mut parts = line.splitn(2, ' ');
+        match (parts.next().and_then(|c| u64::from_str_radix(c, 16).ok()), parts.next()) {
+            (Some(checksum), Some(path)) => expected.insert(path.to_owned(), checksum),
+            _ => return Ok(false),
+        };
+    }
+
+    Ok(checksums(dir)? == expected)
+}
+
+/// Log of the last sysroot build, next to the sysroot's `lib` directory
+const LOG: &str = "build.log";
+
+/// How many logs are kept, including the one of the last build
+const LOGS: usize = 5;
+
+/// Shifts `build.log` to `build.log.1`, `build.log.1` to `build.log.2` and so
+/// on, dropping the oldest log
+fn rotate_logs(dir: &Path) {
+    for i in (1..LOGS).rev() {
+        let from = if i == 1 {
+            dir.join(LOG)
+        } else {
+            dir.join(format!("{}.{}", LOG, i - 1))
+        };
+
+        fs::rename(from, dir.join(format!("{}.{}", LOG, i))).ok();
+    }
+}
+
+fn never_quiet(_: &[u8]) -> bool {
+    false
+}
+
+/// Whether `line` is output only Cargo's `-v` produces
+fn is_verbose_output(line: &[u8]) -> bool {
+    let line = String::from_utf8_lossy(line);
+    let line = line.trim_start();
+
+    line.starts_with("Running `") || line.starts_with("Fresh ") || line.starts_with("Dirty ")
+}
+
 fn old_hash(cmode: &CompilationMode, home: &Home) -> Result<Option<u64>> {
     // FIXME this should be `lock_ro`
     let lock = home.lock_rw(cmode.triple())?;
@@ -242,22 +603,36 @@ fn old_hash(cmode: &CompilationMode, home: &Home) -> Result<Option<u64>> {
 /// This information is used to compute the hash
 ///
 /// - Dependencies in `Xargo.toml` for a specific target
-/// - RUSTFLAGS / build.rustflags / target.*.rustflags
+/// - RUSTFLAGS / build.rustflags / target.*.rustflags, minus instrumentation
+/// - The flags of `xargo rustc -- <flags>` that are propagated to the sysroot
 /// - The target specification file, is any
 /// - `[profile.release]` in `Cargo.toml`
-/// - `rustc` commit hash
+/// - `rustc` commit hash, or the compiler's fingerprint if it has none
+/// - Whether the sysroot was only checked rather than built
+/// - The variables of the environment in `HASH_ENV` and `XARGO_PASS_ENV`
+///
+/// The same information is recorded in `provenance.json` to detect collisions
 fn hash(
     cmode: &CompilationMode,
     blueprint: &Blueprint,
     rustflags: &Rustflags,
     ctoml: &Option<cargo::Toml>,
     meta: &VersionMeta,
+    sysroot: &Sysroot,
+    cargo_mode: XargoMode,
 ) -> Result<u64> {
     let mut hasher = DefaultHasher::new();
 
+    // A checked sysroot only contains metadata so it must never be mistaken
+    // for a built one. Built sysroots keep their hash from before this was
+    // added.
+    if cargo_mode == XargoMode::Check {
+        cargo_mode.hash(&mut hasher);
+    }
+
     blueprint.hash(&mut hasher);
 
-    rustflags.hash(&mut hasher);
+    rustflags.for_sysroot().hash(&mut hasher);
 
     cmode.hash(&mut hasher)?;
 
@@ -267,34 +642,128 @@ fn hash(
         }
     }
 
+    for (name, value, hashed) in env() {
+        if hashed {
+            name.hash(&mut hasher);
+            value.hash(&mut hasher);
+        }
+    }
+
     if let Some(ref hash) = meta.commit_hash {
         hash.hash(&mut hasher);
+    } else if let Some(fingerprint) = sysroot.fingerprint() {
+        // e.g. a locally built `rustc`; the compiler itself is all we have to
+        // tell it apart from the one the sysroot was built with
+        fingerprint.hash(&mut hasher);
     }
 
     Ok(hasher.finish())
 }
 
+/// Records what the sysroot is built from, next to its `lib` directory
+const PROVENANCE: &str = "provenance.json";
+
+/// Describes the would-be target sysroot with the same information its hash
+/// is computed from
+///
+/// Unlike the hash this can't collide, so comparing it with the one recorded
+/// when the sysroot was built tells whether the sysroot really is up to date.
+fn provenance(
+    cmode: &CompilationMode,
+    blueprint: &Blueprint,
+    rustflags: &Rustflags,
+    ctoml: &Option<cargo::Toml>,
+    meta: &VersionMeta,
+    sysroot: &Sysroot,
+    cargo_mode: XargoMode,
+) -> Result<serde_json::Value> {
+    let stages = blueprint
+        .stages
+        .iter()
+        .map(|(stage, s)| (stage.to_string(), Value::Table(s.dependencies.clone()).to_string()))
+        .collect::<BTreeMap<_, _>>();
+
+    let spec = match *cmode {
+        CompilationMode::Cross(ref target) => target.spec()?,
+        CompilationMode::Native(_) => None,
+    };
+
+    let profile = ctoml.as_ref().and_then(|t| t.profile()).and_then(|p| p.hashed());
+
+    let rustc = match (meta.commit_hash.as_ref(), sysroot.fingerprint()) {
+        (Some(hash), _) => hash.clone(),
+        (None, Some(fingerprint)) => fingerprint.to_string(),
+        (None, None) => String::new(),
+    };
+
+    let env = env()
+        .into_iter()
+        .filter(|&(_, _, hashed)| hashed)
+        .map(|(name, value, _)| (name, value.to_string_lossy().into_owned()))
+        .collect::<BTreeMap<_, _>>();
+
+    Ok(json!({
+        "check": cargo_mode == XargoMode::Check,
+        "stages": stages,
+        "rustflags": rustflags.for_sysroot().hashed(),
+        "target": cmode.triple(),
+        "spec": spec,
+        "profile": profile.map(|p| p.to_string()),
+        "rustc": rustc,
+        "env": env,
+    }))
+}
+
+/// Names the directory of a sysroot whose hash collided with another
+/// configuration's
+///
+/// This is a hash too, but of a different input than the one that collided.
+fn digest(provenance: &serde_json::Value) -> String {
+    let mut hasher = DefaultHasher::new();
+    provenance.to_string().hash(&mut hasher);
+    format!("{:016x}", hasher.finish())
+}
+
+/// Returns the provenance recorded when the sysroot was built, if any
+fn old_provenance(cmode: &CompilationMode, home: &Home) -> Result<Option<serde_json::Value>> {
+    // FIXME this should be `lock_ro`
+    let lock = home.lock_rw(cmode.triple())?;
+    let pfile = lock.parent().join(PROVENANCE);
+
+    if pfile.exists() {
+        Ok(serde_json::from_str(&util::read(&pfile)?).ok())
+    } else {
+        Ok(None)
+    }
+}
+
+/// Builds the sysroot, if necessary, and returns the home it ended up in
+///
+/// That's `home` unless its sysroot was built from another configuration
+/// with the same hash, in which case the sysroot goes to a disambiguated
+/// directory of `home`, or unless it's a shared cache whose sysroot doesn't
+/// match its manifest and can't be rebuilt, in which case the sysroot is
+/// built in a private home instead.
 pub fn update(
     cmode: &CompilationMode,
-    home: &Home,
+    home: Home,
     root: &Root,
     rustflags: &Rustflags,
+    config: &Config,
     meta: &VersionMeta,
     src: &Src,
     sysroot: &Sysroot,
     verbose: bool,
     message_format: Option<&str>,
+    stdout_to_stderr: bool,
     cargo_mode: XargoMode,
-) -> Result<()> {
-    let ctoml = match cargo_mode {
-        XargoMode::Build => Some(cargo::toml(root)?),
-        XargoMode::Check => {
-            if root.path().join("Cargo.toml").exists() {
-                Some(cargo::toml(root)?)
-            } else {
-                None
-            }
-        }
+    test: bool,
+) -> Result<Home> {
+    // Neither 'xargo-check' nor 'xargo prebuild' require a 'Cargo.toml'
+    let ctoml = if root.path().join("Cargo.toml").exists() {
+        Some(cargo::toml(root)?)
+    } else {
+        None
     };
 
     let (xtoml_parent, xtoml) = xargo::toml(root)?;
@@ -305,38 +774,144 @@ pub fn update(
     // root path.
     let base_path: &Path = xtoml_parent.unwrap_or_else(|| root.path());
 
-    let blueprint = Blueprint::from(xtoml.as_ref(), cmode.triple(), &base_path, &src)?;
+    // `xargo bench` needs the `test` crate, which needs `std`. Benchmarks
+    // with `harness = false` can still work without it.
+    let test = test && {
+        let supported = rustc::supports_std(cmode, verbose)?;
+        if !supported {
+            writeln!(
+                io::stderr(),
+                "warning: {} doesn't support `std`, so the sysroot won't contain the `test` \
+                 crate; only benchmarks with `harness = false` will build",
+                cmode.triple()
+            ).ok();
+        }
+        supported
+    };
+
+    let blueprint = Blueprint::from(xtoml.as_ref(), cmode.triple(), &base_path, &src, test)?;
+
+    if test && !blueprint.contains("test") {
+        writeln!(
+            io::stderr(),
+            "warning: `std` isn't part of the sysroot so the `test` crate can't be either; \
+             add `[dependencies.std]` to Xargo.toml"
+        ).ok();
+    }
+
+    if rustflags.needs_profiler_runtime() && !blueprint.has_profiler_runtime() {
+        writeln!(
+            io::stderr(),
+            "warning: instrumentation needs the `profiler_builtins` crate in the sysroot. \
+             Add `[dependencies.profiler_builtins]` or enable the `profiler` feature of \
+             `std` in Xargo.toml"
+        ).ok();
+    }
+
+    let hash = hash(cmode, &blueprint, rustflags, &ctoml, meta, sysroot, cargo_mode)?;
+    let provenance = provenance(cmode, &blueprint, rustflags, &ctoml, meta, sysroot, cargo_mode)?;
+
+    // Two configurations whose hashes collide can't share a sysroot, or they
+    // would keep replacing each other's. This one gets a directory of its own,
+    // named after its provenance.
+    let mut home = home;
+    let collides = old_hash(cmode, &home)? == Some(hash) && match old_provenance(cmode, &home)? {
+        Some(old) => old != provenance,
+        None => false,
+    };
+    if collides {
+        let disambiguated = home.disambiguated(&digest(&provenance));
+        writeln!(
+            io::stderr(),
+            "warning: the sysroot of {} has the expected hash but was built from a different \
+             configuration; using {} instead",
+            cmode.triple(),
+            disambiguated.display()
+        ).ok();
+        home = disambiguated;
+    }
+
+    // Don't trust a shared sysroot that may have been corrupted by some other
+    // writer. It's rebuilt in place, unless we can't write to it, in which
+    // case we build our own instead.
+    if xargo::shared_cache()
+        && !(repair(&home, cmode.triple())? && (cmode.is_native() || repair(&home, &meta.host)?))
+    {
+        let private = xargo::private_home(cmode)?;
+        writeln!(
+            io::stderr(),
+            "warning: the sysroot in {} is corrupted and can't be rebuilt, using {} instead",
+            home.display(),
+            private.display()
+        ).ok();
+        home = private;
+    }
 
-    let hash = hash(cmode, &blueprint, rustflags, &ctoml, meta)?;
+    let old = old_hash(cmode, &home)?;
+    let old_provenance = old_provenance(cmode, &home)?;
 
-    if old_hash(cmode, home)? != Some(hash) {
+    // Whether the sysroot was built from this blueprint in `cargo_mode`. A
+    // matching hash isn't enough as two configurations may collide; the
+    // provenance tells them apart. Sysroots built before the provenance was
+    // recorded are trusted.
+    let built_from = |blueprint: &Blueprint, cargo_mode: XargoMode| -> Result<bool> {
+        if old != Some(self::hash(cmode, blueprint, rustflags, &ctoml, meta, sysroot, cargo_mode)?) {
+            return Ok(false);
+        }
+
+        let provenance =
+            self::provenance(cmode, blueprint, rustflags, &ctoml, meta, sysroot, cargo_mode)?;
+        match old_provenance {
+            Some(ref old) => Ok(*old == provenance),
+            None => Ok(true),
+        }
+    };
+
+    // A built sysroot is good enough when we only need a checked one
+    let up_to_date = built_from(&blueprint, cargo_mode)?
+        || (cargo_mode == XargoMode::Check && built_from(&blueprint, XargoMode::Build)?);
+
+    // and so is one built for `xargo bench`, which only adds the `test` crate
+    let up_to_date = up_to_date || (!test && old.is_some() && rustc::supports_std(cmode, verbose)? && {
+        let blueprint = Blueprint::from(xtoml.as_ref(), cmode.triple(), base_path, src, true)?;
+        built_from(&blueprint, XargoMode::Build)?
+    });
+
+    if !up_to_date {
         build(
             cmode,
             blueprint,
             &ctoml,
-            home,
+            &home,
             rustflags,
+            config,
             src,
             sysroot,
             hash,
+            &provenance,
             verbose,
             message_format,
+            stdout_to_stderr,
             cargo_mode,
         )?;
     }
 
     // copy host artifacts into the sysroot, if necessary
     if cmode.is_native() {
-        return Ok(());
+        return Ok(home);
     }
 
     let lock = home.lock_rw(&meta.host)?;
     let hfile = lock.parent().join(".hash");
 
-    let hash = meta.commit_hash.as_ref().map(|s| &**s).unwrap_or("");
+    let hash = match (meta.commit_hash.as_ref(), sysroot.fingerprint()) {
+        (Some(hash), _) => hash.clone(),
+        (None, Some(fingerprint)) => fingerprint.to_string(),
+        (None, None) => String::new(),
+    };
     if hfile.exists() {
         if util::read(&hfile)? == hash {
-            return Ok(());
+            return Ok(home);
         }
     }
 
@@ -362,9 +937,15 @@ pub fn update(
         util::cp_r(&bin_src, &bin_dst)?;
     }
 
-    util::write(&hfile, hash)?;
+    if xargo::shared_cache() {
+        write_manifest(lock.parent())?;
+    }
+    util::write(&hfile, &hash)?;
+    if xargo::shared_cache() {
+        home.share(&meta.host)?;
+    }
 
-    Ok(())
+    Ok(home)
 }
 
 /// Per stage dependencies
@@ -377,7 +958,7 @@ pub struct Stage {
 
 /// Which mode to invoke `cargo` in when building the sysroot
 /// Can be either `cargo build` or `cargo check`
-#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
 pub enum XargoMode {
     Build,
     Check,
@@ -445,7 +1026,15 @@ impl Blueprint {
         Ok(())
     }
 
-    fn from(toml: Option<&xargo::Toml>, target: &str, base_path: &Path, src: &Src) -> Result<Self> {
+    /// Reads the blueprint from `Xargo.toml`, adding the `test` crate to the
+    /// stage that contains `std` if `test` is set
+    fn from(
+        toml: Option<&xargo::Toml>,
+        target: &str,
+        base_path: &Path,
+        src: &Src,
+        test: bool,
+    ) -> Result<Self> {
         fn make_path_absolute<F, R>(
             crate_spec: &mut Table,
             base_path: &Path,
@@ -536,6 +1125,13 @@ impl Blueprint {
                     target
                 ))?
             },
+            (None, None) if test => {
+                // Benchmarks need a full `std`, plus `test`
+                let mut t = Map::new();
+                t.insert("std".to_owned(), Value::Table(Map::new()));
+                t.insert("test".to_owned(), Value::Table(Map::new()));
+                t
+            }
             (None, None) => {
                 // If no dependencies were listed, we assume `core` and `compiler_builtins` as the
                 // dependencies
@@ -562,6 +1158,17 @@ impl Blueprint {
             }
         };
 
+        let mut deps = deps;
+        if test && !deps.contains_key("test") {
+            if let Some(std) = deps.get("std").cloned() {
+                let mut krate = Map::new();
+                if let Some(stage) = std.get("stage") {
+                    krate.insert("stage".to_owned(), stage.clone());
+                }
+                deps.insert("test".to_owned(), Value::Table(krate));
+            }
+        }
+
         let mut blueprint = Blueprint::new();
         for (k, v) in deps {
             if let Value::Table(mut map) = v {
@@ -612,6 +1219,25 @@ impl Blueprint {
         stage.crates.push(krate);
     }
 
+    /// Whether `krate` is part of the sysroot
+    fn contains(&self, krate: &str) -> bool {
+        self.stages.values().any(|stage| stage.crates.iter().any(|k| k == krate))
+    }
+
+    /// Whether `profiler_builtins` is part of the sysroot, either directly or
+    /// through some crate's `profiler` feature
+    fn has_profiler_runtime(&self) -> bool {
+        self.stages.values().any(|stage| {
+            stage.dependencies.iter().any(|(k, v)| {
+                k == "profiler_builtins"
+                    || v.get("features")
+                        .and_then(|f| f.as_array())
+                        .map(|f| f.iter().any(|f| f.as_str() == Some("profiler")))
+                        .unwrap_or(false)
+            })
+        })
+    }
+
     fn hash<H>(&self, hasher: &mut H)
     where
         H: Hasher,
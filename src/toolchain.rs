@@ -0,0 +1,128 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::Path;
+use std::process::Command;
+
+use rustc_version::VersionMeta;
+
+use CompilationMode;
+use errors::*;
+use extensions::CommandExt;
+use rustc::Sysroot;
+use xargo::Home;
+use {util, xargo};
+
+/// Lays out `cmode`'s sysroot as a rustup toolchain and registers it as `name`
+/// with `rustup toolchain link`
+///
+/// The toolchain reuses `rustc`, `cargo`, etc. from the current toolchain and
+/// only swaps in the libraries built by Xargo, so rebuilding the sysroot
+/// updates the linked toolchain as well.
+pub fn link(
+    name: &str,
+    cmode: &CompilationMode,
+    home: &Home,
+    sysroot: &Sysroot,
+    meta: &VersionMeta,
+    verbose: bool,
+) -> Result<()> {
+    if home.is_temporary() {
+        bail!(
+            "the sysroot in {} is temporary and can't back a toolchain; set \
+             XARGO_PRIVATE_HOME to keep it",
+            home.display()
+        );
+    }
+
+    let locks = (home.lock_ro(&meta.host)?, home.lock_ro(cmode.triple())?);
+
+    let dst = xargo::toolchain(name)?;
+    if dst.exists() {
+        fs::remove_dir_all(&dst).chain_err(|| format!("couldn't clear {}", dst.display()))?;
+    }
+    fs::create_dir_all(&dst)
+        .chain_err(|| format!("couldn't create directory {}", dst.display()))?;
+
+    // `rustc` locates its sysroot from the path of `librustc_driver` after
+    // resolving symlinks, so the binaries and the dylibs next to them must be
+    // actual files of the new toolchain
+    for dir in &["bin", "lib"] {
+        let src = sysroot.path().join(dir);
+        let dst = dst.join(dir);
+        util::mkdir(&dst)?;
+
+        for entry in read_dir(&src)? {
+            let path = entry.path();
+            if entry.file_name() == "rustlib" {
+                continue;
+            } else if path.is_dir() {
+                util::symlink(&path, &dst.join(entry.file_name()))?;
+            } else {
+                util::link_or_copy(&path, &dst.join(entry.file_name()))?;
+            }
+        }
+    }
+
+    let rustlib_src = sysroot.path().join("lib").join("rustlib");
+    let rustlib_dst = dst.join("lib").join("rustlib");
+    util::mkdir(&rustlib_dst)?;
+
+    // The target libraries come from Xargo's sysroot; everything else (the
+    // linker, codegen backends, ...) from the current toolchain
+    let triple_src = rustlib_src.join(cmode.triple());
+    let triple_dst = rustlib_dst.join(cmode.triple());
+    util::mkdir(&triple_dst)?;
+    util::symlink(&home.rustlib(cmode.triple()).join("lib"), &triple_dst.join("lib"))?;
+    if triple_src.is_dir() {
+        for entry in read_dir(&triple_src)? {
+            if entry.file_name() != "lib" {
+                util::symlink(&entry.path(), &triple_dst.join(entry.file_name()))?;
+            }
+        }
+    }
+
+    for entry in read_dir(&rustlib_src)? {
+        if entry.file_name() != cmode.triple() {
+            util::symlink(&entry.path(), &rustlib_dst.join(entry.file_name()))?;
+        }
+    }
+
+    Command::new("rustup")
+        .args(&["toolchain", "link", name])
+        .arg(&dst)
+        .run(verbose)?;
+
+    drop(locks);
+
+    writeln!(
+        io::stderr(),
+        "{:>12} toolchain `{}` ({}), use it with `cargo +{} build --target {}`",
+        "Linked",
+        name,
+        dst.display(),
+        name,
+        cmode.triple()
+    ).ok();
+
+    Ok(())
+}
+
+/// Prints where the coverage tools of the `llvm-tools` component are, as
+/// `NAME=path` lines that can be exported into the environment
+pub fn llvm_tools(sysroot: &Sysroot, meta: &VersionMeta) -> Result<()> {
+    for &(var, tool) in &[("LLVM_PROFDATA", "llvm-profdata"), ("LLVM_COV", "llvm-cov")] {
+        let path = sysroot.llvm_tool(&meta.host, tool).ok_or_else(|| {
+            format!("`{}` not found. Run `rustup component add llvm-tools`.", tool)
+        })?;
+
+        println!("{}={}", var, path.display());
+    }
+
+    Ok(())
+}
+
+fn read_dir(path: &Path) -> Result<Vec<fs::DirEntry>> {
+    fs::read_dir(path)
+        .and_then(|entries| entries.collect())
+        .chain_err(|| format!("couldn't read the directory {}", path.display()))
+}
@@ -1,7 +1,7 @@
 use std::fs::File;
 use std::io::{Read, Write};
 use std::path::Path;
-use std::fs;
+use std::{env, fs};
 
 use toml::Value;
 use walkdir::WalkDir;
@@ -55,6 +55,107 @@ pub fn cp_r(src: &Path, dst: &Path) -> Result<()> {
     Ok(())
 }
 
+/// Hard links `src` to `dst`, falling back to a copy if that's not possible
+/// (e.g. `dst` is on a different filesystem)
+pub fn link_or_copy(src: &Path, dst: &Path) -> Result<()> {
+    if fs::hard_link(src, dst).is_ok() {
+        return Ok(());
+    }
+
+    fs::copy(src, dst).map(|_| ()).chain_err(|| {
+        format!("couldn't copy {} to {}", src.display(), dst.display())
+    })
+}
+
+#[cfg(unix)]
+pub fn symlink(src: &Path, dst: &Path) -> Result<()> {
+    ::std::os::unix::fs::symlink(src, dst).chain_err(|| {
+        format!("couldn't symlink {} to {}", dst.display(), src.display())
+    })
+}
+
+#[cfg(windows)]
+pub fn symlink(src: &Path, dst: &Path) -> Result<()> {
+    use std::os::windows::fs;
+
+    if src.is_dir() {
+        fs::symlink_dir(src, dst)
+    } else {
+        fs::symlink_file(src, dst)
+    }.chain_err(|| format!("couldn't symlink {} to {}", dst.display(), src.display()))
+}
+
+/// FNV-1a checksum of the contents of `path`
+///
+/// Unlike `DefaultHasher` this is stable across Rust versions, so checksums
+/// written by one Xargo can be verified by another.
+pub fn checksum(path: &Path) -> Result<u64> {
+    let p = path.display();
+    let mut file = File::open(path).chain_err(|| format!("couldn't open {}", p))?;
+    let mut buf = [0; 64 * 1024];
+    let mut hash = 0xcbf2_9ce4_8422_2325u64;
+
+    loop {
+        let n = file.read(&mut buf).chain_err(|| format!("couldn't read {}", p))?;
+        if n == 0 {
+            return Ok(hash);
+        }
+
+        for &b in &buf[..n] {
+            hash ^= u64::from(b);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+}
+
+/// Adds write permission for the group to `path` and, if `recursive`, to its
+/// contents
+#[cfg(unix)]
+pub fn make_group_writable(path: &Path, recursive: bool) -> Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let depth = if recursive { usize::MAX } else { 0 };
+    for e in WalkDir::new(path).max_depth(depth) {
+        let e = e.chain_err(|| format!("couldn't walk {}", path.display()))?;
+        let mut perms = e.metadata()
+            .chain_err(|| format!("couldn't get metadata of {}", e.path().display()))?
+            .permissions();
+
+        perms.set_mode(perms.mode() | 0o020);
+        fs::set_permissions(e.path(), perms)
+            .chain_err(|| format!("couldn't change permissions of {}", e.path().display()))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn make_group_writable(_: &Path, _: bool) -> Result<()> {
+    Ok(())
+}
+
+/// Whether Xargo's stderr is a terminal
+#[cfg(unix)]
+pub fn stderr_is_tty() -> bool {
+    unsafe { ::libc::isatty(2) == 1 }
+}
+
+#[cfg(not(unix))]
+pub fn stderr_is_tty() -> bool {
+    false
+}
+
+/// Whether the environment variable `name` is set to something other than an
+/// empty string, `0`, `false`, `no` or `off`
+pub fn env_flag(name: &str) -> bool {
+    env::var(name)
+        .map(|v| {
+            let v = v.trim().to_lowercase();
+            !["", "0", "false", "no", "off"].contains(&&*v)
+        })
+        .unwrap_or(false)
+}
+
 pub fn mkdir(path: &Path) -> Result<()> {
     fs::create_dir(path).chain_err(|| format!("couldn't create directory {}", path.display()))
 }
@@ -1,15 +1,18 @@
 use std::path::{Display, Path, PathBuf};
-use std::process::ExitStatus;
+use std::process::{self, ExitStatus};
 use std::{env, mem};
 use std::io::{self, Write};
 
+use tempdir::TempDir;
 use toml::Value;
 use rustc_version::VersionMeta;
 
 use CompilationMode;
-use cargo::{Config, Root, Rustflags, Subcommand};
+use cargo::{Root, Rustflags};
 use cli::Args;
+use config::Config;
 use errors::*;
+use rustc::Target;
 use extensions::CommandExt;
 use flock::{FileLock, Filesystem};
 use {cargo, util};
@@ -20,21 +23,38 @@ pub fn run(
     rustflags: Rustflags,
     home: &Home,
     meta: &VersionMeta,
-    config: Option<&Config>,
+    config: &Config,
     verbose: bool,
 ) -> Result<ExitStatus> {
-    let mut cmd = cargo::command();
+    let mut cmd = cargo::command()?;
     cmd.args(args.all());
 
-    if args.subcommand() == Some(Subcommand::Doc) {
+    if args.subcommand().map(|sc| sc.is_doc()).unwrap_or(false) {
         cmd.env(
             "CARGO_ENCODED_RUSTDOCFLAGS",
-            cargo::rustdocflags(config, cmode.triple())?.for_xargo(home),
+            config.rustdocflags(cmode, verbose)?.for_xargo(home),
         );
     }
 
+    // The package is verified from `target/package`, where rustc won't find
+    // a target specification that lives in the project unless it's told
+    // where to look
+    if args.subcommand().map(|sc| sc.is_package()).unwrap_or(false)
+        && env::var_os("RUST_TARGET_PATH").is_none()
+    {
+        if let CompilationMode::Cross(Target::Custom { ref json, .. }) = *cmode {
+            if let Some(dir) = json.parent() {
+                cmd.env("RUST_TARGET_PATH", dir);
+            }
+        }
+    }
+
     let flags = rustflags.build_for_xargo(home);
     if verbose {
+        if let Some(linker) = config.linker(cmode, verbose)? {
+            writeln!(io::stderr(), "+ linker: {}", linker.display()).ok();
+        }
+
         writeln!(io::stderr(), "+ CARGO_ENCODED_RUSTFLAGS={:?}", flags).ok();
     }
     cmd.env("CARGO_ENCODED_RUSTFLAGS", flags);
@@ -50,6 +70,8 @@ pub fn run(
 
 pub struct Home {
     path: Filesystem,
+    // Removes a private home when Xargo is done with it
+    temp: Option<TempDir>,
 }
 
 impl Home {
@@ -57,10 +79,46 @@ impl Home {
         self.path.display()
     }
 
+    /// Returns the home of a configuration whose hash collides with that of
+    /// the sysroot in this home, `disambiguated/$digest` inside it
+    pub fn disambiguated(self, digest: &str) -> Home {
+        Home {
+            path: self.path.join("disambiguated").join(digest),
+            temp: self.temp,
+        }
+    }
+
+    /// Whether the home is removed once Xargo exits
+    pub fn is_temporary(&self) -> bool {
+        self.temp.is_some()
+    }
+
     fn path(&self, triple: &str) -> Filesystem {
         self.path.join("lib").join("rustlib").join(triple)
     }
 
+    /// Returns the `lib/rustlib` directory of `triple`'s sysroot
+    ///
+    /// The caller is responsible for holding a lock on it
+    pub fn rustlib(&self, triple: &str) -> PathBuf {
+        self.path(triple).as_path_unlocked().to_owned()
+    }
+
+    /// Makes the `lib/rustlib` directory of `triple` group writable, as well
+    /// as the directories leading to it
+    pub fn share(&self, triple: &str) -> Result<()> {
+        let root = self.path.as_path_unlocked();
+        let rustlib = root.join("lib").join("rustlib");
+
+        // These may belong to whoever created them first, which is fine as
+        // long as they were shared too
+        for dir in &[root.to_owned(), root.join("lib"), rustlib.clone()] {
+            util::make_group_writable(dir, false).ok();
+        }
+
+        util::make_group_writable(&rustlib.join(triple), true)
+    }
+
     pub fn lock_ro(&self, triple: &str) -> Result<FileLock> {
         let fs = self.path(triple);
 
@@ -80,14 +138,55 @@ impl Home {
     }
 }
 
-pub fn home(cmode: &CompilationMode) -> Result<Home> {
-    let mut p = if let Some(h) = env::var_os("XARGO_HOME") {
-        PathBuf::from(h)
+/// Returns Xargo's directory, `$XARGO_HOME` or `~/.xargo`
+pub fn dir() -> Result<PathBuf> {
+    if let Some(h) = env::var_os("XARGO_HOME") {
+        Ok(PathBuf::from(h))
     } else {
-        dirs::home_dir()
+        Ok(dirs::home_dir()
             .ok_or_else(|| "couldn't find your home directory. Is $HOME set?")?
-            .join(".xargo")
-    };
+            .join(".xargo"))
+    }
+}
+
+/// Returns the directory where the rustup toolchain `name` is laid out,
+/// `$XARGO_HOME/toolchains/$name` for the host's sysroot too
+pub fn toolchain(name: &str) -> Result<PathBuf> {
+    Ok(dir()?.join("toolchains").join(name))
+}
+
+/// Whether `XARGO_HOME` is shared between users or CI jobs
+///
+/// In this mode sysroots are made group writable and verified against their
+/// manifest before use.
+pub fn shared_cache() -> bool {
+    util::env_flag("XARGO_SHARED_CACHE")
+}
+
+pub fn home(cmode: &CompilationMode) -> Result<Home> {
+    home_in(dir()?, cmode)
+}
+
+/// Returns the home to use when the shared one can't be trusted:
+/// `$XARGO_PRIVATE_HOME` or a fresh directory in the temporary directory
+///
+/// The fresh directory belongs to this process alone and is removed when the
+/// returned `Home` is dropped. It's named like a staging directory so that
+/// `sysroot::sweep` cleans it up if Xargo gets killed.
+pub fn private_home(cmode: &CompilationMode) -> Result<Home> {
+    if let Some(p) = env::var_os("XARGO_PRIVATE_HOME") {
+        return home_in(PathBuf::from(p), cmode);
+    }
+
+    let td = TempDir::new(&format!("xargo-{}", process::id()))
+        .chain_err(|| "couldn't create a private sysroot directory")?;
+    let mut home = home_in(td.path().to_owned(), cmode)?;
+    home.temp = Some(td);
+
+    Ok(home)
+}
+
+fn home_in(mut p: PathBuf, cmode: &CompilationMode) -> Result<Home> {
 
     if cmode.is_native() {
         p.push("HOST");
@@ -95,6 +194,7 @@ pub fn home(cmode: &CompilationMode) -> Result<Home> {
 
     Ok(Home {
         path: Filesystem::new(p),
+        temp: None,
     })
 }
 
@@ -116,6 +216,11 @@ impl Toml {
             .and_then(|t| t.get("dependencies"))
     }
 
+    /// Returns the `rustc.propagate` part of `Xargo.toml`
+    pub fn propagate(&self) -> Option<&Value> {
+        self.table.get("rustc").and_then(|t| t.get("propagate"))
+    }
+
     /// Returns the `patch` part of `Xargo.toml`
     pub fn patch(&self) -> Option<&Value> {
         self.table.get("patch")
@@ -6,6 +6,7 @@ extern crate error_chain;
 extern crate lazy_static;
 extern crate parking_lot;
 extern crate rustc_version;
+extern crate serde_json;
 extern crate tempdir;
 extern crate dirs;
 
@@ -93,6 +94,34 @@ fn sysroot_was_built(stderr: &str, target: &str) -> bool {
     })
 }
 
+fn sysroot_was_checked(stderr: &str, target: &str) -> bool {
+    stderr.lines().filter(|l| l.starts_with("+")).any(|l| {
+        l.contains("cargo") && l.contains("check") && l.contains("--target") && l.contains(target)
+            && l.contains("-p") && l.contains("core")
+    })
+}
+
+/// Returns the path of `core`'s rlib in `target`'s sysroot
+fn core_rlib(target: &str) -> Result<PathBuf> {
+    let lib = home()?.join("lib/rustlib").join(target).join("lib");
+
+    fs::read_dir(&lib)
+        .chain_err(|| format!("couldn't read the directory {}", lib.display()))?
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .find(|p| p.file_name().map(|n| n.to_string_lossy().contains("core")).unwrap_or(false))
+        .ok_or_else(|| "core is missing from the sysroot".into())
+}
+
+/// Returns the `CARGO_ENCODED_RUSTFLAGS` the sysroot was built with
+fn sysroot_rustflags(stderr: &str) -> String {
+    stderr
+        .lines()
+        .find(|l| l.starts_with("+ CARGO_ENCODED_RUSTFLAGS="))
+        .unwrap_or("")
+        .to_owned()
+}
+
 fn write(path: &Path, append: bool, contents: &str) -> Result<()> {
     let p = path.display();
     let mut opts = OpenOptions::new();
@@ -111,12 +140,16 @@ fn write(path: &Path, append: bool, contents: &str) -> Result<()> {
         .chain_err(|| format!("couldn't write to {}", p))
 }
 
-fn xargo() -> Result<Command> {
+fn xargo_path() -> Result<PathBuf> {
     let mut p = env::current_exe().chain_err(|| "couldn't get path to current executable")?;
     p.pop();
     p.pop();
     p.push("xargo");
-    Ok(Command::new(p))
+    Ok(p)
+}
+
+fn xargo() -> Result<Command> {
+    Ok(Command::new(xargo_path()?))
 }
 
 fn xargo_check() -> Result<Command> {
@@ -245,6 +278,18 @@ impl Project {
             .run_and_get_stderr()
     }
 
+    /// Calls `xargo build` with extra environment variables and collects STDERR
+    fn build_with_env_and_get_stderr(&self, target: &str, env: &[(&str, &str)]) -> Result<String> {
+        let mut cmd = xargo()?;
+        cmd.args(&["build", "--target", target, "-v"]);
+
+        for &(key, value) in env {
+            cmd.env(key, value);
+        }
+
+        cmd.current_dir(self.td.path()).run_and_get_stderr()
+    }
+
     fn build_from_workdir_and_get_stderr(&self, target: Option<&str>, working_dir: &Path) -> Result<String> {
         let mut cmd = xargo()?;
         // set RUST_TARGET_PATH since target json file not in working dir
@@ -283,6 +328,22 @@ impl Project {
         Ok(())
     }
 
+    /// Calls `xargo doc` and collects STDERR
+    fn doc_and_get_stderr(&self, target: &str) -> Result<String> {
+        xargo()?
+            .args(&["doc", "--target", target, "-v"])
+            .current_dir(self.td.path())
+            .run_and_get_stderr()
+    }
+
+    /// Calls `xargo rustc -- <flags>` and collects STDERR
+    fn rustc_and_get_stderr(&self, target: &str, flags: &[&str]) -> Result<String> {
+        xargo()?
+            .args(&["rustc", "--target", target, "-v", "--"])
+            .args(flags)
+            .current_dir(self.td.path())
+            .run_and_get_stderr()
+    }
 
     /// Adds a `Xargo.toml` to the project
     fn xargo_toml(&self, toml: &str) -> Result<()> {
@@ -487,61 +548,58 @@ fn build_outside_project_dir() {
     assert!(r.is_ok());
 }
 
-/// Test `xargo doc`
+/// A relative path to a target specification in `build.target` is relative to
+/// the directory that contains `.cargo`, not to the working directory
 #[test]
-fn doc() {
+fn config_target_json() {
     fn run() -> Result<()> {
-        const TARGET: &'static str = "thumbv6m-doc-eabi";
+        const TARGET: &'static str = "thumbv6m-config_target_json-eabi";
 
         let project = Project::new(TARGET)?;
-        project.doc(TARGET)?;
-        assert!(exists("core", TARGET)?);
-
-        Ok(())
-    }
-
-    run!()
-}
+        let json = format!("{}.json", TARGET);
+        let targets = project.td.path().join("targets");
+        mkdir(&targets)?;
+        fs::rename(project.td.path().join(&json), targets.join(&json))
+            .chain_err(|| "couldn't move the target specification")?;
 
-/// Check that calling `xargo build` a second time doesn't rebuild the sysroot
-#[test]
-fn twice() {
-    fn run() -> Result<()> {
-        const TARGET: &'static str = "thumbv6m-twice-eabi";
+        project.config(&format!("[build]\ntarget = \"targets/{}\"\n", json))?;
 
-        let project = Project::new(TARGET)?;
-        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+        let stderr = xargo()?
+            .args(&["build", "-v"])
+            .current_dir(project.td.path().join("src"))
+            .run_and_get_stderr()?;
 
         assert!(sysroot_was_built(&stderr, TARGET));
 
-        let stderr = project.build_and_get_stderr(Some(TARGET))?;
-
-        assert!(!sysroot_was_built(&stderr, TARGET));
-
         Ok(())
     }
 
     run!()
 }
 
-/// Check that if `build.target` is set in `.cargo/config`, that target will be
-/// used to build the sysroot
+/// `xargo package` verifies the package against the custom sysroot, and
+/// doesn't need one when it only lists the packaged files
 #[test]
-fn build_target() {
+fn package() {
     fn run() -> Result<()> {
-        const TARGET: &'static str = "thumbv6m-build_target-eabi";
+        const TARGET: &'static str = "thumbv6m-package-eabi";
 
         let project = Project::new(TARGET)?;
-        project.config(
-            r#"
-[build]
-target = "thumbv6m-build_target-eabi"
-"#,
-        )?;
 
-        let stderr = project.build_and_get_stderr(None)?;
+        let stderr = xargo()?
+            .args(&["package", "--list", "--allow-dirty", "--target", TARGET, "-v"])
+            .current_dir(project.td.path())
+            .run_and_get_stderr()?;
+
+        assert!(!sysroot_was_built(&stderr, TARGET));
+
+        let stderr = xargo()?
+            .args(&["package", "--allow-dirty", "--target", TARGET, "-v"])
+            .current_dir(project.td.path())
+            .run_and_get_stderr()?;
 
         assert!(sysroot_was_built(&stderr, TARGET));
+        assert!(stderr.contains("Verifying"), "unexpected stderr:\n{}", stderr);
 
         Ok(())
     }
@@ -549,23 +607,48 @@ target = "thumbv6m-build_target-eabi"
     run!()
 }
 
-/// Check that `--target` overrides `build.target`
+/// Xargo uses the real Cargo when `CARGO` points at a wrapper, like Xargo
+/// itself, and stops if it keeps invoking itself
 #[test]
-fn override_build_target() {
+fn cargo_wrapper() {
     fn run() -> Result<()> {
-        const TARGET: &'static str = "thumbv6m-override_build_target-eabi";
+        const TARGET: &'static str = "thumbv6m-cargo_wrapper-eabi";
 
         let project = Project::new(TARGET)?;
-        project.config(
-            r#"
-[build]
-target = "BAD"
-"#,
-        )?;
-
-        let stderr = project.build_and_get_stderr(Some(TARGET))?;
-
-        assert!(sysroot_was_built(&stderr, TARGET));
+        let wrapper = xargo_path()?;
+
+        project.build_with_env_and_get_stderr(TARGET, &[("CARGO", wrapper.to_str().unwrap())])?;
+
+        let recursive = xargo()?
+            .args(&["build", "--target", TARGET])
+            .env("XARGO_RECURSION_COUNT", "5")
+            .current_dir(project.td.path())
+            .output()
+            .chain_err(|| "couldn't run xargo")?;
+
+        assert!(!recursive.status.success());
+        assert!(String::from_utf8_lossy(&recursive.stderr).contains("recursively"));
+
+        // Without rustup there's no way to find the real Cargo
+        let out = Command::new("rustc")
+            .args(&["--print", "sysroot"])
+            .output()
+            .chain_err(|| "couldn't run rustc")?;
+        let rustc = Path::new(String::from_utf8_lossy(&out.stdout).trim()).join("bin/rustc");
+        let empty = TempDir::new("path").chain_err(|| "couldn't create a temporary directory")?;
+
+        let stranded = xargo()?
+            .arg("clean")
+            .env("CARGO", &wrapper)
+            .env("RUSTC", &rustc)
+            .env("PATH", empty.path())
+            .current_dir(project.td.path())
+            .output()
+            .chain_err(|| "couldn't run xargo")?;
+        let stderr = String::from_utf8_lossy(&stranded.stderr);
+
+        assert!(!stranded.status.success());
+        assert!(stderr.contains("which isn't Cargo"), "unexpected stderr:\n{}", stderr);
 
         Ok(())
     }
@@ -573,27 +656,38 @@ target = "BAD"
     run!()
 }
 
-/// We shouldn't rebuild the sysroot if `profile.release.lto` changed
+/// `xargo --help` documents Xargo's options before Cargo's, and
+/// `xargo completions` prints a script for each supported shell
 #[test]
-fn lto_changed() {
+fn help_and_completions() {
     fn run() -> Result<()> {
-        const TARGET: &'static str = "thumbv6m-lto_changed-eabi";
-
-        let project = Project::new(TARGET)?;
-        let stderr = project.build_and_get_stderr(Some(TARGET))?;
-
-        assert!(sysroot_was_built(&stderr, TARGET));
-
-        project.cargo_toml(
-            r#"
-[profile.release]
-lto = true
-"#,
-        )?;
+        let help = xargo()?
+            .arg("--help")
+            .output()
+            .chain_err(|| "couldn't run xargo")?;
+        let stdout = String::from_utf8_lossy(&help.stdout);
+
+        assert!(help.status.success());
+        assert!(stdout.contains("--targets-file <PATH>"));
+        assert!(stdout.contains("XARGO_RUST_SRC"));
+        assert!(stdout.contains("Cargo's help"));
+
+        for shell in &["bash", "zsh", "fish", "powershell"] {
+            let script = xargo()?
+                .args(&["completions", shell])
+                .output()
+                .chain_err(|| "couldn't run xargo")?;
+
+            assert!(script.status.success());
+            assert!(String::from_utf8_lossy(&script.stdout).contains("xargo-check"));
+        }
 
-        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+        let unknown = xargo()?
+            .args(&["completions", "tcsh"])
+            .output()
+            .chain_err(|| "couldn't run xargo")?;
 
-        assert!(!sysroot_was_built(&stderr, TARGET));
+        assert!(!unknown.status.success());
 
         Ok(())
     }
@@ -601,27 +695,43 @@ lto = true
     run!()
 }
 
-/// Modifying RUSTFLAGS should trigger a rebuild of the sysroot
+/// `XARGO_COVERAGE` instruments the project but not the sysroot, which
+/// lacks the profiler runtime here, and `XARGO_COVERAGE=0` instruments nothing
 #[test]
-fn rustflags_changed() {
+fn coverage() {
     fn run() -> Result<()> {
-        const TARGET: &'static str = "thumbv6m-rustflags_changed-eabi";
+        const TARGET: &'static str = "thumbv6m-coverage-eabi";
+
+        // The flags of the sysroot build are those forcing `unstable`
+        fn rustflags(stderr: &str, sysroot: bool) -> Vec<&str> {
+            stderr
+                .lines()
+                .filter(|l| l.starts_with("+ CARGO_ENCODED_RUSTFLAGS="))
+                .filter(|l| l.contains("force-unstable-if-unmarked") == sysroot)
+                .collect()
+        }
 
         let project = Project::new(TARGET)?;
-        let stderr = project.build_and_get_stderr(Some(TARGET))?;
 
-        assert!(sysroot_was_built(&stderr, TARGET));
+        // Without the runtime the project itself may fail to build
+        let on = xargo()?
+            .args(&["build", "--target", TARGET, "-v"])
+            .env("XARGO_COVERAGE", "1")
+            .current_dir(project.td.path())
+            .output()
+            .chain_err(|| "couldn't run xargo")?;
+        let stderr = String::from_utf8_lossy(&on.stderr);
 
-        project.config(
-            r#"
-[build]
-rustflags = ["--cfg", "xargo"]
-"#,
-        )?;
+        assert!(stderr.contains("warning: instrumentation needs the `profiler_builtins` crate"));
+        assert!(!rustflags(&stderr, true).is_empty());
+        assert!(rustflags(&stderr, true).iter().all(|l| !l.contains("instrument-coverage")));
+        assert!(!rustflags(&stderr, false).is_empty());
+        assert!(rustflags(&stderr, false).iter().all(|l| l.contains("instrument-coverage")));
 
-        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+        let off = project.build_with_env_and_get_stderr(TARGET, &[("XARGO_COVERAGE", "0")])?;
 
-        assert!(sysroot_was_built(&stderr, TARGET));
+        assert!(!off.contains("instrument-coverage"));
+        assert!(!off.contains("profiler_builtins"));
 
         Ok(())
     }
@@ -629,30 +739,32 @@ rustflags = ["--cfg", "xargo"]
     run!()
 }
 
-/// Check that RUSTFLAGS are passed to all `rustc`s
+/// `xargo sysroot llvm-tools` prints where `llvm-profdata` and `llvm-cov`
+/// are, or how to install them
 #[test]
-fn rustflags() {
+fn llvm_tools() {
     fn run() -> Result<()> {
-        const TARGET: &'static str = "thumbv6m-rustflags-eabi";
+        let out = xargo()?
+            .args(&["sysroot", "llvm-tools"])
+            .output()
+            .chain_err(|| "couldn't run xargo")?;
 
-        let project = Project::new(TARGET)?;
+        if out.status.success() {
+            let stdout = String::from_utf8_lossy(&out.stdout);
 
-        project.config(
-            r#"
-[build]
-rustflags = ["--cfg", "xargo"]
-"#,
-        )?;
+            for var in &["LLVM_PROFDATA=", "LLVM_COV="] {
+                let line = stdout
+                    .lines()
+                    .find(|l| l.starts_with(var))
+                    .ok_or_else(|| format!("{} is missing from:\n{}", var, stdout))?;
 
-        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+                assert!(Path::new(&line[var.len()..]).is_file());
+            }
+        } else {
+            let stderr = String::from_utf8_lossy(&out.stderr);
 
-        assert!(
-            stderr
-                .lines()
-                .filter(|l| !l.starts_with("+") && l.contains("rustc") && !l.contains("rustc-std-workspace"))
-                .all(|l| l.contains("--cfg") && l.contains("xargo")),
-            "unexpected stderr:\n{}", stderr
-        );
+            assert!(stderr.contains("rustup component add llvm-tools"));
+        }
 
         Ok(())
     }
@@ -660,29 +772,45 @@ rustflags = ["--cfg", "xargo"]
     run!()
 }
 
-/// Check that `-C panic=abort` is passed to `rustc` when `panic = "abort"` is
-/// set in `profile.release`
+/// `xargo sysroot link` lays out a toolchain whose target libraries are
+/// Xargo's sysroot and registers it with rustup
 #[test]
-fn panic_abort() {
+fn sysroot_link() {
     fn run() -> Result<()> {
-        const TARGET: &'static str = "thumbv6m-panic_abort-eabi";
+        const TARGET: &'static str = "thumbv6m-sysroot_link-eabi";
 
         let project = Project::new(TARGET)?;
 
-        project.cargo_toml(
-            r#"
-[profile.release]
-panic = "abort"
-"#,
-        )?;
+        // A `rustup` that only records how it was called
+        let bin = TempDir::new("rustup").chain_err(|| "couldn't create a temporary directory")?;
+        let rustup = bin.path().join("rustup");
+        let args = bin.path().join("args");
+        write(&rustup, false, &format!("#!/bin/sh\necho \"$@\" > {}\n", args.display()))?;
+        Command::new("chmod").arg("+x").arg(&rustup).run()?;
 
-        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+        let path = env::var_os("PATH").ok_or("PATH is not set")?;
+        let mut paths = vec![bin.path().to_owned()];
+        paths.extend(env::split_paths(&path));
+        let path = env::join_paths(paths).chain_err(|| "couldn't extend PATH")?;
 
-        assert!(
-            stderr
-                .lines()
-                .filter(|l| !l.starts_with("+") && l.contains("--release"))
-                .all(|l| l.contains("-C") && l.contains("panic=abort"))
+        xargo()?
+            .args(&["sysroot", "link", "xargo-sysroot_link", "--target", TARGET])
+            .env("PATH", path)
+            .current_dir(project.td.path())
+            .run()?;
+
+        let toolchain = home()?.join("toolchains").join("xargo-sysroot_link");
+        let lib = fs::read_link(toolchain.join("lib/rustlib").join(TARGET).join("lib"))
+            .chain_err(|| "the target libraries aren't a symlink")?;
+
+        assert_eq!(lib, home()?.join("lib/rustlib").join(TARGET).join("lib"));
+        assert!(exists("core", TARGET)?);
+
+        let args = fs::read_to_string(&args).chain_err(|| "rustup wasn't called")?;
+
+        assert_eq!(
+            args.trim(),
+            format!("toolchain link xargo-sysroot_link {}", toolchain.display())
         );
 
         Ok(())
@@ -691,28 +819,75 @@ panic = "abort"
     run!()
 }
 
-/// Check that adding linker arguments doesn't trigger a sysroot rebuild
+/// A `rustc` without a commit hash is told apart by its fingerprint, so
+/// touching its `rustc_driver` re-resolves the version and the host's sysroot
+#[cfg(unix)]
 #[test]
-fn link_arg() {
+fn rustc_fingerprint() {
     fn run() -> Result<()> {
-        const TARGET: &'static str = "thumbv6m-link_arg-eabi";
+        const TARGET: &'static str = "thumbv6m-rustc_fingerprint-eabi";
 
         let project = Project::new(TARGET)?;
+        let xargo_home = TempDir::new("xargo_home").chain_err(|| "couldn't create a temporary directory")?;
+
+        let out = Command::new("rustc")
+            .args(&["--print", "sysroot"])
+            .output()
+            .chain_err(|| "couldn't run rustc")?;
+        let sysroot = String::from_utf8_lossy(&out.stdout);
+        let sysroot = Path::new(sysroot.trim());
+
+        // A sysroot that borrows everything from the real one but its
+        // `rustc_driver`, and a `rustc` that reports it without a commit hash
+        let stub = TempDir::new("rustc").chain_err(|| "couldn't create a temporary directory")?;
+        let fake = stub.path().join("sysroot");
+        mkdir(&fake)?;
+        mkdir(&fake.join("bin"))?;
+        mkdir(&fake.join("lib"))?;
+        std::os::unix::fs::symlink(sysroot.join("bin/rustc"), fake.join("bin/rustc"))
+            .chain_err(|| "couldn't link rustc")?;
+        std::os::unix::fs::symlink(sysroot.join("lib/rustlib"), fake.join("lib/rustlib"))
+            .chain_err(|| "couldn't link rustlib")?;
+        let driver = fake.join("lib/librustc_driver-fake.so");
+        write(&driver, false, "")?;
+
+        let rustc = stub.path().join("rustc");
+        write(
+            &rustc,
+            false,
+            &format!(
+                "#!/bin/sh\n\
+                 case \"$*\" in\n\
+                 -vV) {0} -vV | sed 's/^commit-hash: .*/commit-hash: unknown/' ;;\n\
+                 \"--print sysroot\") echo {1} ;;\n\
+                 *) exec {0} \"$@\" ;;\n\
+                 esac\n",
+                sysroot.join("bin/rustc").display(),
+                fake.display()
+            ),
+        )?;
+        Command::new("chmod").arg("+x").arg(&rustc).run()?;
 
-        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+        let env = [
+            ("RUSTC", rustc.to_str().unwrap()),
+            ("XARGO_HOME", xargo_home.path().to_str().unwrap()),
+        ];
+        let hfile = xargo_home.path().join("lib/rustlib").join(host()).join(".hash");
 
+        let stderr = project.build_with_env_and_get_stderr(TARGET, &env)?;
         assert!(sysroot_was_built(&stderr, TARGET));
+        let hash = fs::read_to_string(&hfile).chain_err(|| "the host's sysroot is missing")?;
 
-        project.config(
-            r#"
-[target.__link_arg]
-rustflags = ["-C", "link-arg=-lfoo"]
-"#,
-        )?;
+        let stderr = project.build_with_env_and_get_stderr(TARGET, &env)?;
+        assert!(!sysroot_was_built(&stderr, TARGET));
+        assert_eq!(fs::read_to_string(&hfile).chain_err(|| "the host's sysroot is missing")?, hash);
 
-        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+        Command::new("touch").args(&["-t", "200001010000"]).arg(&driver).run()?;
 
-        assert!(!sysroot_was_built(&stderr, TARGET));
+        let stderr = project.build_with_env_and_get_stderr(TARGET, &env)?;
+        assert!(stderr.contains("\"-vV\""), "rustc -vV wasn't run:\n{}", stderr);
+        assert!(sysroot_was_built(&stderr, TARGET));
+        assert_ne!(fs::read_to_string(&hfile).chain_err(|| "the host's sysroot is missing")?, hash);
 
         Ok(())
     }
@@ -720,41 +895,50 @@ rustflags = ["-C", "link-arg=-lfoo"]
     run!()
 }
 
-/// The sysroot should be rebuilt if the target specification changed
+/// Old staging directories are swept once the Xargo that created them is
+/// gone, including those of Xargos that didn't record their PID
 #[test]
-fn specification_changed() {
+fn sweep_staging() {
     fn run() -> Result<()> {
-        const JSON: &'static str = r#"
-{
-    "arch": "arm",
-    "data-layout": "e-m:e-p:32:32-i64:64-v128:64:128-a:0:32-n32-S64",
-    "linker-flavor": "gcc",
-    "llvm-target": "thumbv6m-none-eabi",
-    "max-atomic-width": 0,
-    "os": "none",
-    "panic-strategy": "abort",
-    "target-c-int-width": "32",
-    "target-endian": "little",
-    "target-pointer-width": "32"
-}
-"#;
-        const TARGET: &'static str = "thumbv6m-specification_changed-eabi";
+        const TARGET: &'static str = "thumbv6m-sweep_staging-eabi";
 
-        let project = Project::new(TARGET)?;
+        let tmp = TempDir::new("sweep").chain_err(|| "couldn't create a temporary directory")?;
 
-        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+        let dead = tmp.path().join("xargo-999999999.aaaaaaaaaaaa");
+        let live = tmp.path().join(format!("xargo-{}.aaaaaaaaaaaa", std::process::id()));
+        let legacy = tmp.path().join("xargo.aaaaaaaaaaaa");
+        let recent = tmp.path().join("xargo.bbbbbbbbbbbb");
+        let kept = tmp.path().join("xargo-keep-999999999.aaaaaaaaaaaa");
 
-        assert!(sysroot_was_built(&stderr, TARGET));
+        for dir in &[&dead, &live, &legacy, &recent, &kept] {
+            fs::create_dir(dir).chain_err(|| format!("couldn't create {}", dir.display()))?;
+        }
+        for dir in &[&dead, &live, &legacy, &kept] {
+            Command::new("touch").args(&["-t", "200001010000"]).arg(dir).run()?;
+        }
 
-        write(
-            &project.td.path().join("thumbv6m-specification_changed-eabi.json"),
-            false,
-            JSON,
-        )?;
+        // Commands that don't build a sysroot don't sweep
+        xargo()?
+            .arg("--version")
+            .env("TMPDIR", tmp.path())
+            .output()
+            .chain_err(|| "couldn't run xargo")?;
 
-        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+        assert!(dead.exists());
 
-        assert!(sysroot_was_built(&stderr, TARGET));
+        let project = Project::new(TARGET)?;
+        xargo()?
+            .args(&["build", "--target", TARGET])
+            .env("TMPDIR", tmp.path())
+            .current_dir(project.td.path())
+            .output()
+            .chain_err(|| "couldn't run xargo")?;
+
+        assert!(!dead.exists());
+        assert!(live.exists());
+        assert!(!legacy.exists());
+        assert!(recent.exists());
+        assert!(kept.exists());
 
         Ok(())
     }
@@ -762,41 +946,37 @@ fn specification_changed() {
     run!()
 }
 
-/// The sysroot should NOT be rebuilt if the target specification didn't really
-/// changed, e.g. some fields were moved around
+/// `xargo doctor` checks the target and explains how to fix a missing one
 #[test]
-fn unchanged_specification() {
+fn doctor() {
     fn run() -> Result<()> {
-        const JSON: &'static str = r#"
-{
-    "arch": "arm",
-    "data-layout": "e-m:e-p:32:32-i64:64-v128:64:128-a:0:32-n32-S64",
-    "linker-flavor": "gcc",
-    "llvm-target": "thumbv6m-none-eabi",
-    "os": "none",
-    "max-atomic-width": 0,
-    "target-c-int-width": "32",
-    "target-endian": "little",
-    "target-pointer-width": "32"
-}
-"#;
-        const TARGET: &'static str = "thumbv6m-unchanged_specification-eabi";
+        const TARGET: &'static str = "thumbv6m-doctor-eabi";
 
         let project = Project::new(TARGET)?;
 
-        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+        let found = xargo()?
+            .args(&["doctor", "--target", TARGET])
+            .current_dir(project.td.path())
+            .output()
+            .chain_err(|| "couldn't run xargo")?;
+        let stdout = String::from_utf8_lossy(&found.stdout);
 
-        assert!(sysroot_was_built(&stderr, TARGET));
+        assert!(stdout.contains("ok: the target thumbv6m-doctor-eabi has a valid specification"));
 
-        write(
-            &project.td.path().join("thumbv6m-unchanged_specification-eabi.json"),
-            false,
-            JSON,
-        )?;
+        let missing = xargo()?
+            .args(&["doctor", "--target", "thumbv6m-missing-eabi"])
+            .current_dir(project.td.path())
+            .output()
+            .chain_err(|| "couldn't run xargo")?;
+        let stdout = String::from_utf8_lossy(&missing.stdout);
 
-        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+        assert!(!missing.status.success());
+        assert!(stdout.contains("error: couldn't find the target thumbv6m-missing-eabi"));
+        assert!(stdout.contains("RUST_TARGET_PATH"));
+        assert!(stdout.lines().any(|l| l.starts_with("found ")), "unexpected stdout:\n{}", stdout);
 
-        assert!(!sysroot_was_built(&stderr, TARGET));
+        let stderr = String::from_utf8_lossy(&missing.stderr);
+        assert!(!stderr.contains("error:"), "unexpected stderr:\n{}", stderr);
 
         Ok(())
     }
@@ -804,16 +984,15 @@ fn unchanged_specification() {
     run!()
 }
 
-/// Check that a sysroot is built for the host
+/// Test `xargo doc`
 #[test]
-fn host_once() {
+fn doc() {
     fn run() -> Result<()> {
-        let target = host();
-        let project = HProject::new(false)?;
-
-        let stderr = project.build_and_get_stderr()?;
+        const TARGET: &'static str = "thumbv6m-doc-eabi";
 
-        assert!(sysroot_was_built(&stderr, &target));
+        let project = Project::new(TARGET)?;
+        project.doc(TARGET)?;
+        assert!(exists("core", TARGET)?);
 
         Ok(())
     }
@@ -821,15 +1000,1104 @@ fn host_once() {
     run!()
 }
 
-/// Check that the sysroot is not rebuilt when `xargo build` is called a second
-/// time
+/// `xargo doc` only needs a checked sysroot, which `xargo build` must not reuse
 #[test]
-fn host_twice() {
+fn doc_checks_sysroot() {
     fn run() -> Result<()> {
-        let target = host();
-        let project = HProject::new(false)?;
+        const TARGET: &'static str = "thumbv6m-doc_checks_sysroot-eabi";
 
-        let stderr = project.build_and_get_stderr()?;
+        let project = Project::new(TARGET)?;
+        let stderr = project.doc_and_get_stderr(TARGET)?;
+
+        assert!(sysroot_was_checked(&stderr, TARGET));
+        assert!(!sysroot_was_built(&stderr, TARGET));
+
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(sysroot_was_built(&stderr, TARGET));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// `xargo doc` reuses a sysroot previously built by `xargo build`
+#[test]
+fn doc_after_build() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-doc_after_build-eabi";
+
+        let project = Project::new(TARGET)?;
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(sysroot_was_built(&stderr, TARGET));
+
+        let stderr = project.doc_and_get_stderr(TARGET)?;
+
+        assert!(!sysroot_was_checked(&stderr, TARGET));
+        assert!(!sysroot_was_built(&stderr, TARGET));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// `xargo prebuild` builds the same sysroot that `xargo build` would use
+#[test]
+fn prebuild() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-prebuild-eabi";
+
+        let project = Project::new(TARGET)?;
+        let out = xargo()?
+            .args(&["prebuild", "--message-format", "json", TARGET])
+            .current_dir(project.td.path())
+            .output()
+            .chain_err(|| "couldn't run xargo")?;
+        let stdout = String::from_utf8_lossy(&out.stdout);
+
+        assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+        assert!(exists("core", TARGET)?);
+
+        // Cargo's own JSON messages go to stderr and don't mix with the
+        // progress messages
+        for line in stdout.lines() {
+            let message = serde_json::from_str::<serde_json::Value>(line)
+                .chain_err(|| format!("not JSON: {}", line))?;
+            let reason = message["reason"].as_str().unwrap_or("");
+
+            assert!(reason.starts_with("sysroot-"), "unexpected message: {}", line);
+        }
+        assert_eq!(stdout.lines().count(), 2, "unexpected stdout:\n{}", stdout);
+
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(!sysroot_was_built(&stderr, TARGET));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// The value of a Cargo option in front of the subcommand isn't mistaken for
+/// the subcommand, and `--targets-file` is only Xargo's for `xargo prebuild`
+#[test]
+fn cargo_options_with_values() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-cargo_options_with_values-eabi";
+
+        let project = Project::new(TARGET)?;
+        let stderr = xargo()?
+            .args(&["--config", "build.incremental=false", "build", "--target", TARGET, "-v"])
+            .current_dir(project.td.path())
+            .run_and_get_stderr()?;
+
+        assert!(sysroot_was_built(&stderr, TARGET));
+
+        let targets = project.td.path().join("targets.txt");
+        write(&targets, false, &format!("{}\n", TARGET))?;
+
+        let out = xargo()?
+            .args(&["prebuild", "--targets-file", targets.to_str().unwrap()])
+            .current_dir(project.td.path())
+            .output()
+            .chain_err(|| "couldn't run xargo")?;
+        let stdout = String::from_utf8_lossy(&out.stdout);
+
+        assert!(out.status.success(), "{}", String::from_utf8_lossy(&out.stderr));
+        assert!(stdout.contains(TARGET), "unexpected stdout:\n{}", stdout);
+
+        // Cargo rejects it, rather than Xargo taking it for its own
+        let out = xargo()?
+            .args(&["build", "--targets-file", targets.to_str().unwrap(), "--target", TARGET])
+            .current_dir(project.td.path())
+            .output()
+            .chain_err(|| "couldn't run xargo")?;
+        let stderr = String::from_utf8_lossy(&out.stderr);
+
+        assert!(!out.status.success());
+        assert!(stderr.contains("--targets-file"), "unexpected stderr:\n{}", stderr);
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that calling `xargo build` a second time doesn't rebuild the sysroot
+#[test]
+fn twice() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-twice-eabi";
+
+        let project = Project::new(TARGET)?;
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(sysroot_was_built(&stderr, TARGET));
+
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(!sysroot_was_built(&stderr, TARGET));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// The output of the sysroot build is logged, including the `rustc`
+/// invocations, even when not running with `-v`
+#[test]
+fn build_log() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-build_log-eabi";
+
+        let project = Project::new(TARGET)?;
+        project.build(TARGET)?;
+
+        let dir = home()?.join("lib/rustlib").join(TARGET);
+        let log = fs::read_to_string(dir.join("build.log"))
+            .chain_err(|| "couldn't read build.log")?;
+
+        assert!(log.contains("Running `"), "unexpected build.log:\n{}", log);
+
+        project.config(
+            r#"
+[build]
+rustflags = ["--cfg", "xargo"]
+"#,
+        )?;
+        project.build(TARGET)?;
+
+        assert!(dir.join("build.log.1").exists());
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// The sysroot is built in a clean environment; variables only get through,
+/// and into the hash, when Xargo knows about them or they are listed in
+/// `XARGO_PASS_ENV`
+#[test]
+fn sandboxed_env() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-sandboxed_env-eabi";
+
+        let project = Project::new(TARGET)?;
+        let stderr = project.build_with_env_and_get_stderr(TARGET, &[])?;
+
+        assert!(sysroot_was_built(&stderr, TARGET));
+
+        let stderr =
+            project.build_with_env_and_get_stderr(TARGET, &[("XARGO_SANDBOX_TEST", "1")])?;
+
+        assert!(!sysroot_was_built(&stderr, TARGET));
+
+        let stderr = project.build_with_env_and_get_stderr(
+            TARGET,
+            &[
+                ("XARGO_SANDBOX_TEST", "1"),
+                ("XARGO_PASS_ENV", "XARGO_SANDBOX_TEST"),
+                ("GIT_SSH_COMMAND", "ssh"),
+                ("VCINSTALLDIR", "vc"),
+            ],
+        )?;
+
+        assert!(sysroot_was_built(&stderr, TARGET));
+
+        let log = fs::read_to_string(home()?.join("lib/rustlib").join(TARGET).join("build.log"))
+            .chain_err(|| "couldn't read build.log")?;
+
+        assert!(log.contains("XARGO_SANDBOX_TEST"), "unexpected build.log:\n{}", log);
+        assert!(log.contains("GIT_SSH_COMMAND"), "unexpected build.log:\n{}", log);
+        assert!(log.contains("VCINSTALLDIR"), "unexpected build.log:\n{}", log);
+
+        // Passed through, but not part of the hash
+        let stderr = project.build_with_env_and_get_stderr(
+            TARGET,
+            &[
+                ("XARGO_SANDBOX_TEST", "1"),
+                ("XARGO_PASS_ENV", "XARGO_SANDBOX_TEST"),
+                ("GIT_SSH_COMMAND", "ssh -v"),
+                ("VCINSTALLDIR", "other-vc"),
+            ],
+        )?;
+
+        assert!(!sysroot_was_built(&stderr, TARGET));
+
+        // Changes what `cc` emits
+        let stderr = project.build_with_env_and_get_stderr(
+            TARGET,
+            &[
+                ("XARGO_SANDBOX_TEST", "1"),
+                ("XARGO_PASS_ENV", "XARGO_SANDBOX_TEST"),
+                ("MACOSX_DEPLOYMENT_TARGET", "10.12"),
+            ],
+        )?;
+
+        assert!(sysroot_was_built(&stderr, TARGET));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// A sysroot whose hash matches but whose recorded provenance doesn't, as if
+/// two configurations collided, is built again in a disambiguated directory
+#[test]
+fn hash_collision() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-hash_collision-eabi";
+
+        let project = Project::new(TARGET)?;
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(sysroot_was_built(&stderr, TARGET));
+
+        let provenance = home()?.join("lib/rustlib").join(TARGET).join("provenance.json");
+        assert!(provenance.exists());
+        write(&provenance, false, r#"{"rustc": "some other rustc"}"#)?;
+
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(stderr.contains("different configuration"), "unexpected stderr:\n{}", stderr);
+        assert!(sysroot_was_built(&stderr, TARGET));
+
+        // The other configuration's sysroot is left alone
+        assert_eq!(
+            fs::read_to_string(&provenance).chain_err(|| "couldn't read provenance.json")?,
+            r#"{"rustc": "some other rustc"}"#
+        );
+
+        let disambiguated = home()?.join("disambiguated");
+        let dirs = fs::read_dir(&disambiguated)
+            .chain_err(|| format!("couldn't read the directory {}", disambiguated.display()))?
+            .filter_map(|e| e.ok())
+            .filter(|e| e.path().join("lib/rustlib").join(TARGET).join("lib").exists())
+            .count();
+
+        assert_eq!(dirs, 1);
+
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(!sysroot_was_built(&stderr, TARGET));
+        assert!(stderr.contains("disambiguated"), "unexpected stderr:\n{}", stderr);
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// In shared cache mode a sysroot that doesn't match its manifest is rebuilt
+/// in place, and used again from then on
+#[test]
+fn shared_cache_corrupted() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-shared_cache_corrupted-eabi";
+
+        let project = Project::new(TARGET)?;
+        let private = TempDir::new("xargo_private").chain_err(|| "couldn't create a temporary directory")?;
+        let private_home = private.path().to_str().unwrap();
+        let env = [("XARGO_SHARED_CACHE", "1"), ("XARGO_PRIVATE_HOME", private_home)];
+
+        let stderr = project.build_with_env_and_get_stderr(TARGET, &env)?;
+
+        assert!(sysroot_was_built(&stderr, TARGET));
+
+        write(&core_rlib(TARGET)?, true, "garbage")?;
+
+        let stderr = project.build_with_env_and_get_stderr(TARGET, &env)?;
+
+        assert!(stderr.contains("doesn't match its manifest"), "unexpected stderr:\n{}", stderr);
+        assert!(sysroot_was_built(&stderr, TARGET));
+        assert!(!private.path().join("lib").exists());
+
+        let stderr = project.build_with_env_and_get_stderr(TARGET, &env)?;
+
+        assert!(!stderr.contains("warning"), "unexpected stderr:\n{}", stderr);
+        assert!(!sysroot_was_built(&stderr, TARGET));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// A sysroot built before `XARGO_SHARED_CACHE` was set has no manifest, so
+/// it's rebuilt once it's shared
+#[test]
+fn shared_cache_without_manifest() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-shared_cache_without_manifest-eabi";
+
+        let project = Project::new(TARGET)?;
+        let env = [("XARGO_SHARED_CACHE", "1")];
+
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(sysroot_was_built(&stderr, TARGET));
+
+        let stderr = project.build_with_env_and_get_stderr(TARGET, &env)?;
+
+        assert!(stderr.contains("doesn't match its manifest"), "unexpected stderr:\n{}", stderr);
+        assert!(sysroot_was_built(&stderr, TARGET));
+        assert!(home()?.join("lib/rustlib").join(TARGET).join("manifest").exists());
+
+        let stderr = project.build_with_env_and_get_stderr(TARGET, &env)?;
+
+        assert!(!sysroot_was_built(&stderr, TARGET));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// `XARGO_SHARED_CACHE=0` leaves the shared mode off
+#[test]
+fn shared_cache_off() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-shared_cache_off-eabi";
+
+        let project = Project::new(TARGET)?;
+        let stderr = project.build_with_env_and_get_stderr(TARGET, &[("XARGO_SHARED_CACHE", "0")])?;
+
+        assert!(sysroot_was_built(&stderr, TARGET));
+        assert!(!home()?.join("lib/rustlib").join(TARGET).join("manifest").exists());
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Without `XARGO_PRIVATE_HOME`, every run that can't trust the shared cache
+/// builds in a home of its own, which is removed afterwards
+#[test]
+fn shared_cache_private_homes() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-shared_cache_private_homes-eabi";
+
+        let project = Project::new(TARGET)?;
+        let tmp = TempDir::new("xargo_tmp").chain_err(|| "couldn't create a temporary directory")?;
+        let tmpdir = tmp.path().to_str().unwrap();
+        let env = [("XARGO_SHARED_CACHE", "1"), ("TMPDIR", tmpdir)];
+
+        project.build_with_env_and_get_stderr(TARGET, &env)?;
+
+        write(&core_rlib(TARGET)?, true, "garbage")?;
+
+        // A lock file that can't be opened for writing, not even by root,
+        // keeps the shared sysroot from being rebuilt
+        let sentinel = home()?.join("lib/rustlib").join(TARGET).join(".sentinel");
+        fs::remove_file(&sentinel).chain_err(|| "couldn't remove the lock file")?;
+        mkdir(&sentinel)?;
+
+        let mut homes = vec![];
+        for _ in 0..2 {
+            let stderr = project.build_with_env_and_get_stderr(TARGET, &env)?;
+
+            assert!(sysroot_was_built(&stderr, TARGET));
+            let home = stderr
+                .lines()
+                .filter_map(|l| l.split(", using ").nth(1))
+                .next()
+                .and_then(|l| l.split(" instead").next())
+                .ok_or_else(|| format!("no private home in stderr:\n{}", stderr))?
+                .to_owned();
+
+            assert!(home.starts_with(tmpdir), "{} isn't in {}", home, tmpdir);
+            assert!(!Path::new(&home).exists(), "{} was left behind", home);
+            homes.push(home);
+        }
+
+        assert_ne!(homes[0], homes[1]);
+
+        // Once the shared sysroot is writable again it's rebuilt in place and
+        // used from then on
+        fs::remove_dir(&sentinel).chain_err(|| "couldn't remove the lock file")?;
+
+        let stderr = project.build_with_env_and_get_stderr(TARGET, &env)?;
+
+        assert!(stderr.contains("doesn't match its manifest"), "unexpected stderr:\n{}", stderr);
+        assert!(!stderr.contains(tmpdir), "unexpected stderr:\n{}", stderr);
+        assert!(sysroot_was_built(&stderr, TARGET));
+
+        let stderr = project.build_with_env_and_get_stderr(TARGET, &env)?;
+
+        assert!(!sysroot_was_built(&stderr, TARGET));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that if `build.target` is set in `.cargo/config`, that target will be
+/// used to build the sysroot
+#[test]
+fn build_target() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-build_target-eabi";
+
+        let project = Project::new(TARGET)?;
+        project.config(
+            r#"
+[build]
+target = "thumbv6m-build_target-eabi"
+"#,
+        )?;
+
+        let stderr = project.build_and_get_stderr(None)?;
+
+        assert!(sysroot_was_built(&stderr, TARGET));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that `--target` overrides `build.target`
+#[test]
+fn override_build_target() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-override_build_target-eabi";
+
+        let project = Project::new(TARGET)?;
+        project.config(
+            r#"
+[build]
+target = "BAD"
+"#,
+        )?;
+
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(sysroot_was_built(&stderr, TARGET));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// We shouldn't rebuild the sysroot if `profile.release.lto` changed
+#[test]
+fn lto_changed() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-lto_changed-eabi";
+
+        let project = Project::new(TARGET)?;
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(sysroot_was_built(&stderr, TARGET));
+
+        project.cargo_toml(
+            r#"
+[profile.release]
+lto = true
+"#,
+        )?;
+
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(!sysroot_was_built(&stderr, TARGET));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Modifying RUSTFLAGS should trigger a rebuild of the sysroot
+#[test]
+fn rustflags_changed() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-rustflags_changed-eabi";
+
+        let project = Project::new(TARGET)?;
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(sysroot_was_built(&stderr, TARGET));
+
+        project.config(
+            r#"
+[build]
+rustflags = ["--cfg", "xargo"]
+"#,
+        )?;
+
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(sysroot_was_built(&stderr, TARGET));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that RUSTFLAGS are passed to all `rustc`s
+#[test]
+fn rustflags() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-rustflags-eabi";
+
+        let project = Project::new(TARGET)?;
+
+        project.config(
+            r#"
+[build]
+rustflags = ["--cfg", "xargo"]
+"#,
+        )?;
+
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(
+            stderr
+                .lines()
+                .filter(|l| !l.starts_with("+") && l.contains("rustc") && !l.contains("rustc-std-workspace"))
+                .all(|l| l.contains("--cfg") && l.contains("xargo")),
+            "unexpected stderr:\n{}", stderr
+        );
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// `target.$triple.rustflags` and the matching `target.'cfg(..)'.rustflags` are
+/// joined together and take precedence over `build.rustflags`
+#[test]
+fn rustflags_target_and_cfg() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-rustflags_target_and_cfg-eabi";
+
+        let project = Project::new(TARGET)?;
+
+        project.config(
+            r#"
+[build]
+rustflags = ["--cfg", "xargo_build"]
+
+[target.thumbv6m-rustflags_target_and_cfg-eabi]
+rustflags = ["--cfg", "xargo_target"]
+
+[target.'cfg(all(target_os = "none", not(unix)))']
+rustflags = "--cfg xargo_cfg"
+
+[target.'cfg(target_os = "linux")']
+rustflags = ["--cfg", "xargo_linux"]
+"#,
+        )?;
+
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+        let flags = sysroot_rustflags(&stderr);
+
+        assert!(flags.contains("xargo_target"), "unexpected flags: {}", flags);
+        assert!(flags.contains("xargo_cfg"), "unexpected flags: {}", flags);
+        assert!(flags.find("xargo_target") < flags.find("xargo_cfg"), "unexpected flags: {}", flags);
+        assert!(!flags.contains("xargo_build"), "unexpected flags: {}", flags);
+        assert!(!flags.contains("xargo_linux"), "unexpected flags: {}", flags);
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// `build.rustflags` is used when no `target.*.rustflags` applies
+#[test]
+fn rustflags_build_fallback() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-rustflags_build_fallback-eabi";
+
+        let project = Project::new(TARGET)?;
+
+        project.config(
+            r#"
+[build]
+rustflags = "--cfg xargo_build"
+
+[target.thumbv6m-rustflags_build_fallback-eabi]
+rustflags = []
+
+[target.thumbv6m-none-eabi]
+rustflags = ["--cfg", "xargo_other"]
+
+[target.'cfg(target_os = "linux")']
+rustflags = ["--cfg", "xargo_linux"]
+"#,
+        )?;
+
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+        let flags = sysroot_rustflags(&stderr);
+
+        assert!(flags.contains("xargo_build"), "unexpected flags: {}", flags);
+        assert!(!flags.contains("xargo_other"), "unexpected flags: {}", flags);
+        assert!(!flags.contains("xargo_linux"), "unexpected flags: {}", flags);
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// ABI affecting flags of `xargo rustc -- <flags>` reach the sysroot, which
+/// flags do can be changed in `Xargo.toml`
+#[test]
+fn rustc_flags() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-rustc_flags-eabi";
+
+        let project = Project::new(TARGET)?;
+
+        let flags = ["-C", "target-cpu=cortex-m0", "--cfg", "xargo_rustc"];
+        let stderr = project.rustc_and_get_stderr(TARGET, &flags)?;
+        let sysroot_flags = sysroot_rustflags(&stderr);
+
+        assert!(sysroot_was_built(&stderr, TARGET));
+        assert!(sysroot_flags.contains("target-cpu=cortex-m0"), "unexpected flags: {}", sysroot_flags);
+        assert!(!sysroot_flags.contains("xargo_rustc"), "unexpected flags: {}", sysroot_flags);
+
+        // The flags are part of the hash
+        let stderr = project.rustc_and_get_stderr(TARGET, &flags)?;
+        assert!(!sysroot_was_built(&stderr, TARGET));
+
+        project.xargo_toml(
+            r#"
+[rustc]
+propagate = ["--cfg"]
+"#,
+        )?;
+
+        let stderr = project.rustc_and_get_stderr(TARGET, &flags)?;
+        let sysroot_flags = sysroot_rustflags(&stderr);
+
+        assert!(sysroot_was_built(&stderr, TARGET));
+        assert!(!sysroot_flags.contains("target-cpu"), "unexpected flags: {}", sysroot_flags);
+        assert!(sysroot_flags.contains("xargo_rustc"), "unexpected flags: {}", sysroot_flags);
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// The target may come from `CARGO_BUILD_TARGET`, which overrides
+/// `build.target`; its `target.$triple.rustflags` apply to the sysroot
+#[test]
+fn build_target_env() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-build_target_env-eabi";
+
+        let project = Project::new(TARGET)?;
+
+        project.config(
+            r#"
+[build]
+target = "thumbv7m-none-eabi"
+
+[target.thumbv6m-build_target_env-eabi]
+rustflags = ["--cfg", "xargo_target"]
+"#,
+        )?;
+
+        let stderr = xargo()?
+            .args(&["build", "-v"])
+            .env("CARGO_BUILD_TARGET", TARGET)
+            .current_dir(project.td.path())
+            .run_and_get_stderr()?;
+        let flags = sysroot_rustflags(&stderr);
+
+        assert!(sysroot_was_built(&stderr, TARGET));
+        assert!(flags.contains("xargo_target"), "unexpected flags: {}", flags);
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Unlike `--target`, `build.target` may name a target that's also a file in
+/// the working directory
+#[test]
+fn build_target_file() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-build_target_file-eabi";
+
+        let project = Project::new(TARGET)?;
+        project.config(&format!("[build]\ntarget = \"{}\"\n", TARGET))?;
+        write(&project.td.path().join(TARGET), false, "")?;
+
+        let stderr = xargo()?
+            .args(&["build", "-v"])
+            .current_dir(project.td.path())
+            .run_and_get_stderr()?;
+
+        assert!(sysroot_was_built(&stderr, TARGET));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// `build.jobs`, `CARGO_BUILD_JOBS` and `--jobs` apply to the sysroot build,
+/// in the same order of precedence as for the project
+#[test]
+fn jobs() {
+    fn run() -> Result<()> {
+        const CONFIG: &'static str = "thumbv6m-jobs_config-eabi";
+        const CLI: &'static str = "thumbv6m-jobs_cli-eabi";
+
+        let project = Project::new(CONFIG)?;
+        project.config("[build]\njobs = 1\n")?;
+
+        let stderr = project.build_with_env_and_get_stderr(CONFIG, &[])?;
+
+        assert!(sysroot_was_built(&stderr, CONFIG));
+        assert!(stderr.contains("\"-j\" \"1\""), "unexpected stderr:\n{}", stderr);
+
+        let project = Project::new(CLI)?;
+        project.config("[build]\njobs = 1\n")?;
+
+        let stderr = xargo()?
+            .args(&["build", "--target", CLI, "-v", "-j", "2"])
+            .env("CARGO_BUILD_JOBS", "3")
+            .current_dir(project.td.path())
+            .run_and_get_stderr()?;
+
+        assert!(sysroot_was_built(&stderr, CLI));
+        assert!(stderr.contains("\"-j\" \"2\""), "unexpected stderr:\n{}", stderr);
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// A `linker` of `.cargo/config` that's a path is relative to the directory
+/// that contains `.cargo`, and `CARGO_TARGET_$TRIPLE_LINKER` overrides it
+#[test]
+fn linker() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-linker-eabi";
+
+        let project = Project::new(TARGET)?;
+        project.config(&format!("[target.{}]\nlinker = \"tools/ld\"\n", TARGET))?;
+
+        let stderr = project.build_with_env_and_get_stderr(TARGET, &[])?;
+        let ld = project.td.path().join("tools/ld");
+
+        assert!(stderr.contains(&format!("+ linker: {}", ld.display())));
+
+        let stderr = project.build_with_env_and_get_stderr(
+            TARGET,
+            &[("CARGO_TARGET_THUMBV6M_LINKER_EABI_LINKER", "other-ld")],
+        )?;
+
+        assert!(stderr.contains("+ linker: other-ld"));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// `RUSTFLAGS` overrides `.cargo/config` and `CARGO_ENCODED_RUSTFLAGS`
+/// overrides `RUSTFLAGS`
+#[test]
+fn rustflags_env() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-rustflags_env-eabi";
+
+        let project = Project::new(TARGET)?;
+
+        project.config(
+            r#"
+[build]
+rustflags = ["--cfg", "xargo_build"]
+
+[target.thumbv6m-rustflags_env-eabi]
+rustflags = ["--cfg", "xargo_target"]
+"#,
+        )?;
+
+        let stderr =
+            project.build_with_env_and_get_stderr(TARGET, &[("RUSTFLAGS", "--cfg xargo_env")])?;
+        let flags = sysroot_rustflags(&stderr);
+
+        assert!(flags.contains("xargo_env"), "unexpected flags: {}", flags);
+        assert!(!flags.contains("xargo_build"), "unexpected flags: {}", flags);
+        assert!(!flags.contains("xargo_target"), "unexpected flags: {}", flags);
+
+        let stderr = project.build_with_env_and_get_stderr(
+            TARGET,
+            &[
+                ("RUSTFLAGS", "--cfg xargo_env"),
+                ("CARGO_ENCODED_RUSTFLAGS", "--cfg\x1fxargo_encoded"),
+            ],
+        )?;
+        let flags = sysroot_rustflags(&stderr);
+
+        assert!(flags.contains("xargo_encoded"), "unexpected flags: {}", flags);
+        assert!(!flags.contains("xargo_env"), "unexpected flags: {}", flags);
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// `CARGO_TARGET_$TRIPLE_RUSTFLAGS` is joined with `target.$triple.rustflags`,
+/// `CARGO_BUILD_RUSTFLAGS` with `build.rustflags`, and `RUSTFLAGS` overrides
+/// both
+#[test]
+fn rustflags_cargo_env() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-rustflags_cargo_env-eabi";
+        const BUILD: &'static str = "thumbv6m-rustflags_cargo_env_build-eabi";
+
+        let project = Project::new(TARGET)?;
+        project.config(
+            r#"
+[build]
+rustflags = ["--cfg", "xargo_build"]
+
+[target.thumbv6m-rustflags_cargo_env-eabi]
+rustflags = ["--cfg", "xargo_target"]
+"#,
+        )?;
+
+        let target_env = (
+            "CARGO_TARGET_THUMBV6M_RUSTFLAGS_CARGO_ENV_EABI_RUSTFLAGS",
+            "--cfg xargo_target_env",
+        );
+        let build_env = ("CARGO_BUILD_RUSTFLAGS", "--cfg xargo_build_env");
+
+        let stderr = project.build_with_env_and_get_stderr(TARGET, &[target_env, build_env])?;
+        let flags = sysroot_rustflags(&stderr);
+
+        assert!(flags.contains("xargo_target"), "unexpected flags: {}", flags);
+        assert!(flags.contains("xargo_target_env"), "unexpected flags: {}", flags);
+        assert!(
+            flags.find("xargo_target\\u{1f}") < flags.find("xargo_target_env"),
+            "unexpected flags: {}",
+            flags
+        );
+        assert!(!flags.contains("xargo_build"), "unexpected flags: {}", flags);
+
+        let stderr = project.build_with_env_and_get_stderr(
+            TARGET,
+            &[target_env, build_env, ("RUSTFLAGS", "--cfg xargo_env")],
+        )?;
+        let flags = sysroot_rustflags(&stderr);
+
+        assert!(flags.contains("xargo_env"), "unexpected flags: {}", flags);
+        assert!(!flags.contains("xargo_target"), "unexpected flags: {}", flags);
+        assert!(!flags.contains("xargo_build"), "unexpected flags: {}", flags);
+
+        let project = Project::new(BUILD)?;
+        project.config("[build]\nrustflags = [\"--cfg\", \"xargo_build\"]\n")?;
+
+        let stderr = project.build_with_env_and_get_stderr(BUILD, &[build_env])?;
+        let flags = sysroot_rustflags(&stderr);
+
+        assert!(flags.contains("xargo_build"), "unexpected flags: {}", flags);
+        assert!(flags.contains("xargo_build_env"), "unexpected flags: {}", flags);
+        assert!(
+            flags.find("xargo_build\\u{1f}") < flags.find("xargo_build_env"),
+            "unexpected flags: {}",
+            flags
+        );
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that `-C panic=abort` is passed to `rustc` when `panic = "abort"` is
+/// set in `profile.release`
+#[test]
+fn panic_abort() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-panic_abort-eabi";
+
+        let project = Project::new(TARGET)?;
+
+        project.cargo_toml(
+            r#"
+[profile.release]
+panic = "abort"
+"#,
+        )?;
+
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(
+            stderr
+                .lines()
+                .filter(|l| !l.starts_with("+") && l.contains("--release"))
+                .all(|l| l.contains("-C") && l.contains("panic=abort"))
+        );
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that adding linker arguments doesn't trigger a sysroot rebuild
+#[test]
+fn link_arg() {
+    fn run() -> Result<()> {
+        const TARGET: &'static str = "thumbv6m-link_arg-eabi";
+
+        let project = Project::new(TARGET)?;
+
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(sysroot_was_built(&stderr, TARGET));
+
+        project.config(
+            r#"
+[target.__link_arg]
+rustflags = ["-C", "link-arg=-lfoo"]
+"#,
+        )?;
+
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(!sysroot_was_built(&stderr, TARGET));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// The sysroot should be rebuilt if the target specification changed
+#[test]
+fn specification_changed() {
+    fn run() -> Result<()> {
+        const JSON: &'static str = r#"
+{
+    "arch": "arm",
+    "data-layout": "e-m:e-p:32:32-i64:64-v128:64:128-a:0:32-n32-S64",
+    "linker-flavor": "gcc",
+    "llvm-target": "thumbv6m-none-eabi",
+    "max-atomic-width": 0,
+    "os": "none",
+    "panic-strategy": "abort",
+    "target-c-int-width": "32",
+    "target-endian": "little",
+    "target-pointer-width": "32"
+}
+"#;
+        const TARGET: &'static str = "thumbv6m-specification_changed-eabi";
+
+        let project = Project::new(TARGET)?;
+
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(sysroot_was_built(&stderr, TARGET));
+
+        write(
+            &project.td.path().join("thumbv6m-specification_changed-eabi.json"),
+            false,
+            JSON,
+        )?;
+
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(sysroot_was_built(&stderr, TARGET));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// The sysroot should NOT be rebuilt if the target specification didn't really
+/// changed, e.g. some fields were moved around
+#[test]
+fn unchanged_specification() {
+    fn run() -> Result<()> {
+        const JSON: &'static str = r#"
+{
+    "arch": "arm",
+    "data-layout": "e-m:e-p:32:32-i64:64-v128:64:128-a:0:32-n32-S64",
+    "linker-flavor": "gcc",
+    "llvm-target": "thumbv6m-none-eabi",
+    "os": "none",
+    "max-atomic-width": 0,
+    "target-c-int-width": "32",
+    "target-endian": "little",
+    "target-pointer-width": "32"
+}
+"#;
+        const TARGET: &'static str = "thumbv6m-unchanged_specification-eabi";
+
+        let project = Project::new(TARGET)?;
+
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(sysroot_was_built(&stderr, TARGET));
+
+        write(
+            &project.td.path().join("thumbv6m-unchanged_specification-eabi.json"),
+            false,
+            JSON,
+        )?;
+
+        let stderr = project.build_and_get_stderr(Some(TARGET))?;
+
+        assert!(!sysroot_was_built(&stderr, TARGET));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that a sysroot is built for the host
+#[test]
+fn host_once() {
+    fn run() -> Result<()> {
+        let target = host();
+        let project = HProject::new(false)?;
+
+        let stderr = project.build_and_get_stderr()?;
+
+        assert!(sysroot_was_built(&stderr, &target));
+
+        Ok(())
+    }
+
+    run!()
+}
+
+/// Check that the sysroot is not rebuilt when `xargo build` is called a second
+/// time
+#[test]
+fn host_twice() {
+    fn run() -> Result<()> {
+        let target = host();
+        let project = HProject::new(false)?;
+
+        let stderr = project.build_and_get_stderr()?;
 
         assert!(sysroot_was_built(&stderr, &target));
 
@@ -864,6 +2132,25 @@ features = [\"panic_unwind\"]
     run!()
 }
 
+/// Check that `xargo bench` adds the `test` crate to the sysroot
+#[test]
+fn host_bench() {
+    fn run() -> Result<()> {
+        let project = HProject::new(true)?;
+
+        project.xargo_toml(
+            "
+[dependencies.std]
+features = [\"panic_unwind\"]
+",
+        )?;
+
+        project.build("bench")
+    }
+
+    run!()
+}
+
 /// Check multi stage sysroot builds with `xargo build`
 #[test]
 fn host_liballoc() {